@@ -2,6 +2,7 @@ use image::{ColorType, ImageFormat, RgbImage};
 use rand::thread_rng;
 use simple_wfc::grid_2d::{Coordinate2d, Direction2d, Grid2d};
 use simple_wfc::rules::*;
+use simple_wfc::tiled::{Orientation, Symmetry, TiledModel};
 use simple_wfc::*;
 
 type S = StateSet;
@@ -12,25 +13,6 @@ const WIDTH_TILES: u32 = 40;
 const HEIGHT_TILES: u32 = 40;
 
 fn main() {
-    StateSet::scope(11, || {
-        test();
-    });
-}
-
-#[allow(non_snake_case)]
-fn test() {
-    let A = State::nth(0);
-    let B = State::nth(1);
-    let C = State::nth(2);
-    let D = State::nth(3);
-    let E = State::nth(4);
-    let F = State::nth(5);
-    let G = State::nth(6);
-    let H = State::nth(7);
-    let I = State::nth(8);
-    let J = State::nth(9);
-    let K = State::nth(10);
-
     //
     //  A B C J
     //  D E F K
@@ -38,107 +20,49 @@ fn test() {
     //
     //
     // A-I form a 9-quadrant for rectangles, J is open space around them, and K can touch only J
+    //
+    // A, C, G and I are rotations of one corner tile (G), and B, D, F and H are rotations of
+    // one edge tile (B).
+
+    let mut model = TiledModel::new();
+    let corner = model.add_tile(Symmetry::L);
+    let edge = model.add_tile(Symmetry::T);
+    let inside = model.add_tile(Symmetry::X);
+    let open = model.add_tile(Symmetry::X);
+    let speck = model.add_tile(Symmetry::X);
+
+    let o = Orientation::default();
+    let quarter = Orientation::new(1, false);
+    let half = Orientation::new(2, false);
+
+    // Rectangles.
+    model.allow((edge, o), Direction2d::Up, (inside, o));
+    model.allow((inside, o), Direction2d::Right, (inside, o));
+    model.allow((edge, o), Direction2d::Right, (edge, o));
+    model.allow((edge, o), Direction2d::Right, (corner, half));
+
+    // Open space around rectangles.
+    model.allow((edge, o), Direction2d::Down, (open, o));
+    model.allow((corner, o), Direction2d::Left, (open, o));
+    model.allow((open, o), Direction2d::Right, (open, o));
+    model.allow((open, o), Direction2d::Right, (speck, o));
+
+    // Rectangles touching each other.
+    model.allow((edge, o), Direction2d::Down, (edge, half));
+    model.allow((edge, o), Direction2d::Down, (corner, o));
+    model.allow((corner, o), Direction2d::Up, (corner, quarter));
+    model.allow((corner, o), Direction2d::Up, (corner, half));
+
+    let rule = model.rules(UniformSetCollapseObserver);
 
-    let rule = SetCollapseRulesBuilder::<Grid2d<StateSet>, _>::new(UniformSetCollapseObserver)
-        .allow(
-            E,
-            &[
-                (Direction2d::Down, E.clone() | B.clone()),
-                (Direction2d::Left, E.clone() | D.clone()),
-                (Direction2d::Right, E.clone() | F.clone()),
-                (Direction2d::Up, E.clone() | H.clone()),
-            ],
-        )
-        .allow(
-            A,
-            &[
-                (Direction2d::Left, C.clone() | F.clone() | I.clone()),
-                (Direction2d::Down, G.clone() | H.clone() | I.clone()),
-            ],
-        )
-        .allow(
-            B,
-            &[
-                (Direction2d::Left, A.clone() | B.clone()),
-                (Direction2d::Right, C.clone() | B.clone()),
-                (Direction2d::Down, G.clone() | H.clone() | I.clone()),
-            ],
-        )
-        .allow(
-            C,
-            &[
-                (Direction2d::Down, G.clone() | H.clone() | I.clone()),
-                (Direction2d::Right, A.clone() | D.clone() | G.clone()),
-            ],
-        )
-        .allow(
-            G,
-            &[
-                (Direction2d::Up, A.clone() | B.clone() | C.clone()),
-                (Direction2d::Left, C.clone() | F.clone() | I.clone()),
-            ],
-        )
-        .allow(
-            I,
-            &[
-                (Direction2d::Right, A.clone() | D.clone() | G.clone()),
-                (Direction2d::Up, A.clone() | B.clone() | C.clone()),
-            ],
-        )
-        .allow(
-            H,
-            &[
-                (Direction2d::Left, G.clone() | H.clone()),
-                (Direction2d::Right, I.clone() | H.clone()),
-                (Direction2d::Up, A.clone() | B.clone() | C.clone()),
-            ],
-        )
-        .allow(
-            F,
-            &[
-                (Direction2d::Down, C.clone() | F.clone()),
-                (Direction2d::Up, I.clone() | F.clone()),
-                (Direction2d::Right, A.clone() | D.clone() | C.clone()),
-            ],
-        )
-        .allow(
-            D,
-            &[
-                (Direction2d::Down, A.clone() | D.clone()),
-                (Direction2d::Up, G.clone() | D.clone()),
-                (Direction2d::Left, C.clone() | F.clone() | I.clone()),
-            ],
-        )
-        .allow(
-            J,
-            &[
-                (
-                    Direction2d::Down,
-                    J.clone() | G.clone() | H.clone() | I.clone() | K.clone(),
-                ),
-                (
-                    Direction2d::Up,
-                    J.clone() | A.clone() | B.clone() | C.clone() | K.clone(),
-                ),
-                (
-                    Direction2d::Left,
-                    J.clone() | C.clone() | F.clone() | I.clone() | K.clone(),
-                ),
-                (
-                    Direction2d::Right,
-                    J.clone() | A.clone() | D.clone() | G.clone() | K.clone(),
-                ),
-            ],
-        )
-        .build();
     let mut grid = Grid::new(
         Coordinate2d {
             x: WIDTH_TILES,
             y: HEIGHT_TILES,
         },
-        |_| S::all(),
+        |_| rule.domain().all(),
     );
-    collapse(&mut grid, &rule, &mut thread_rng(), |_| {});
+    collapse(&mut grid, &rule, &mut thread_rng(), |_| {}).unwrap();
 
     let image_bytes = include_bytes!("pattern.png");
     let input_image = image::load_from_memory_with_format(&image_bytes[..], ImageFormat::Png)
@@ -150,58 +74,21 @@ fn test() {
         for x in 0..WIDTH_TILES {
             let image_start_x = x * 8;
             let image_start_y = y * 8;
-            let tile_start_x;
-            let tile_start_y;
-            match grid[Coordinate2d { x, y }].clone() {
-                a if a.has(A) => {
-                    tile_start_x = 0;
-                    tile_start_y = 0;
-                }
-                a if a.has(B) => {
-                    tile_start_x = 8;
-                    tile_start_y = 0;
-                }
-                a if a.has(C) => {
-                    tile_start_x = 16;
-                    tile_start_y = 0;
-                }
-                a if a.has(D) => {
-                    tile_start_x = 0;
-                    tile_start_y = 8;
-                }
-                a if a.has(E) => {
-                    tile_start_x = 8;
-                    tile_start_y = 8;
-                }
-                a if a.has(F) => {
-                    tile_start_x = 16;
-                    tile_start_y = 8;
-                }
-                a if a.has(G) => {
-                    tile_start_x = 0;
-                    tile_start_y = 16;
-                }
-                a if a.has(H) => {
-                    tile_start_x = 8;
-                    tile_start_y = 16;
-                }
-                a if a.has(I) => {
-                    tile_start_x = 16;
-                    tile_start_y = 16;
-                }
-                a if a.has(J) => {
-                    tile_start_x = 24;
-                    tile_start_y = 0;
-                }
-                a if a.has(K) => {
-                    tile_start_x = 24;
-                    tile_start_y = 8;
-                }
-                a => panic!("unknown state {a:?}!"),
-            }
+            let state = grid[Coordinate2d { x, y }].iter().next().unwrap();
+            let (tile, orientation) = model.variant(state);
+            let (tile_start_x, tile_start_y) = match tile {
+                t if t == corner => (0, 16),
+                t if t == edge => (8, 0),
+                t if t == inside => (8, 8),
+                t if t == open => (24, 0),
+                t if t == speck => (24, 8),
+                t => panic!("unknown tile {t:?}!"),
+            };
             for j in 0..8 {
                 for i in 0..8 {
                     let pixel = input_image.get_pixel(tile_start_x + i, tile_start_y + j);
+                    let Coordinate2d { x: i, y: j } =
+                        orientation.transform(Coordinate2d { x: i, y: j }, 8);
                     output_image.put_pixel(image_start_x + i, image_start_y + j, *pixel);
                 }
             }
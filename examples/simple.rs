@@ -1,6 +1,6 @@
-use rand::thread_rng;
+use rand::{rngs::StdRng, thread_rng, Rng};
 use simple_wfc::{
-    collapse,
+    collapse_with_retries,
     grid_2d::{Axis2d, Coordinate2d, Grid2d},
     overlapping::{codify_patterns, Tile},
     Space, StateSet,
@@ -38,44 +38,52 @@ ____________________
 
     println!("rules: {}", rule.state_count());
 
-    StateSet::scope(rule.state_count(), || {
-        let start_collapse = Instant::now();
+    let start_collapse = Instant::now();
 
-        let dimensions = Coordinate2d { x: 100, y: 100 };
-        let mut space = Grid2d::new(dimensions, |coord| {
-            let mut state = StateSet::all();
+    let dimensions = Coordinate2d { x: 100, y: 100 };
+    let mut space = Grid2d::new(dimensions, |coord| {
+        let mut state = rule.domain().all();
 
-            if coord.x == 0
-                || coord.y == 0
-                || coord.x == dimensions.x - 1
-                || coord.y == dimensions.y - 1
-            {
-                state.retain(|s| rule.observer().center(s).is_none());
-            }
+        if coord.x == 0
+            || coord.y == 0
+            || coord.x == dimensions.x - 1
+            || coord.y == dimensions.y - 1
+        {
+            state.retain(|s| rule.observer().center(s).is_none());
+        }
 
-            state
-        });
+        state
+    });
 
-        collapse(&mut space, &rule, &mut thread_rng(), |_progress| {
+    let outcome = collapse_with_retries::<_, _, StdRng, _>(
+        &mut space,
+        &rule,
+        thread_rng().gen(),
+        10,
+        |_progress| {
             //println!("{:.2}", _progress.resolved as f32 / _progress.total as f32);
-        });
+        },
+    );
 
-        let collapse_time = start_collapse.elapsed().as_secs_f32();
+    let collapse_time = start_collapse.elapsed().as_secs_f32();
 
-        let start_decode = Instant::now();
+    let start_decode = Instant::now();
 
-        let (unextracted, overconstrained) =
-            rule.observer().decode_superposition::<Grid2d<_>, _>(&space);
+    let (unextracted, overconstrained) =
+        rule.observer().decode_superposition::<Grid2d<_>, _>(&space);
 
-        let decode_time = start_decode.elapsed().as_secs_f32();
+    let decode_time = start_decode.elapsed().as_secs_f32();
 
-        println!("overconstrained: {overconstrained}");
-        println!("collapse: {collapse_time:.3}s decode: {decode_time:.3}s");
-        println!("output:");
-        print_grid(&unextracted);
+    println!("attempts: {}", outcome.attempts);
+    if let Err(contradiction) = outcome.result {
+        println!("{contradiction}");
+    }
+    println!("overconstrained: {overconstrained}");
+    println!("collapse: {collapse_time:.3}s decode: {decode_time:.3}s");
+    println!("output:");
+    print_grid(&unextracted);
 
-        //println!("{unextracted:?}");
-    });
+    //println!("{unextracted:?}");
 
     if let Ok(report) = profiler.report().build() {
         let mut buf = Vec::new();
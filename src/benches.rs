@@ -2,8 +2,10 @@ extern crate test;
 use crate::{
     collapse,
     grid_2d::{Axis2d, Coordinate2d, Grid2d},
-    overlapping::{codify_patterns, Tile},
-    Space, StateSet,
+    overlapping::{codify_patterns, ExtractedPatterns, Tile},
+    rules::SetCollapseRules,
+    sockets::tests::pipes,
+    Collapser, InlineStateSet, Propagation, Space, StateSet, Superposition,
 };
 use rand::thread_rng;
 use test::{black_box, Bencher};
@@ -21,6 +23,71 @@ use test::{black_box, Bencher};
 
 #[bench]
 fn wfc_3x3_2d(b: &mut Bencher) {
+    let rule = rule_3x3_2d();
+    let domain = rule.domain();
+
+    b.iter(move || {
+        let mut space = Grid2d::new(Coordinate2d { x: 20, y: 20 }, |_| domain.all());
+
+        let _ = black_box(collapse(
+            black_box(&mut space),
+            black_box(&rule),
+            &mut thread_rng(),
+            |_| {},
+        ));
+        black_box(rule.observer().decode_superposition::<Grid2d<_>, _>(&space));
+    });
+}
+
+#[bench]
+fn wfc_3x3_2d_support_count(b: &mut Bencher) {
+    let rule = rule_3x3_2d();
+    let domain = rule.domain();
+
+    b.iter(move || {
+        let mut space = Grid2d::new(Coordinate2d { x: 20, y: 20 }, |_| domain.all());
+
+        let _ = black_box(
+            Collapser::new(&space)
+                .with_propagation(Propagation::SupportCount)
+                .run(
+                    black_box(&mut space),
+                    black_box(&rule),
+                    &mut thread_rng(),
+                    |_| {},
+                ),
+        );
+        black_box(rule.observer().decode_superposition::<Grid2d<_>, _>(&space));
+    });
+}
+
+#[bench]
+fn wfc_pipes_2d(b: &mut Bencher) {
+    bench_pipes_2d::<StateSet>(b);
+}
+
+#[bench]
+fn wfc_pipes_2d_inline(b: &mut Bencher) {
+    bench_pipes_2d::<InlineStateSet<1>>(b);
+}
+
+fn bench_pipes_2d<S: Superposition>(b: &mut Bencher) {
+    let rule = pipes::<Grid2d<S>, S>();
+    let domain = rule.domain();
+
+    b.iter(move || {
+        let mut space = Grid2d::new(Coordinate2d { x: 40, y: 40 }, |_| S::all(domain));
+
+        let _ = black_box(collapse(
+            black_box(&mut space),
+            black_box(&rule),
+            &mut thread_rng(),
+            |_| {},
+        ));
+    });
+}
+
+fn rule_3x3_2d() -> SetCollapseRules<ExtractedPatterns<CharTile>> {
     let input = parse_grid(
         r#"
 ____________________
@@ -35,26 +102,12 @@ ____________________
  "#,
     );
 
-    let rule = codify_patterns::<_, _, Grid2d<StateSet>>(
+    codify_patterns::<_, _, Grid2d<StateSet>>(
         &input,
         Coordinate2d { x: 3, y: 3 },
         &[Axis2d::X, Axis2d::Y],
         Some(()),
-    );
-
-    StateSet::scope(rule.state_count(), || {
-        b.iter(move || {
-            let mut space = Grid2d::new(Coordinate2d { x: 20, y: 20 }, |_| StateSet::all());
-
-            collapse(
-                black_box(&mut space),
-                black_box(&rule),
-                &mut thread_rng(),
-                |_| {},
-            );
-            black_box(rule.observer().decode_superposition::<Grid2d<_>, _>(&space));
-        })
-    });
+    )
 }
 
 fn parse_grid(s: &str) -> Grid2d<Option<CharTile>> {
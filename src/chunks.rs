@@ -0,0 +1,275 @@
+//! Chunked generation of unbounded 2D worlds.
+
+use crate::{
+    collapse,
+    grid_2d::{Coordinate2d, Grid2d},
+    rules::{SetCollapseObserver, SetCollapseRules},
+    Cause, Contradiction, Space, StateSet, Superposition,
+};
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+
+/// Generates an unbounded 2D world in square chunks, on demand.
+///
+/// Each chunk is collapsed in a [`Grid2d`] surrounded by a ring of cells,
+/// where cells of already-generated chunks are fixed, and cells that haven't
+/// been generated yet are collapsed too (and then discarded), so that the
+/// chunk can be extended. Optionally, cells of already-generated chunks within
+/// a margin of the new chunk are regenerated along with it, as in model
+/// synthesis, giving it more room to satisfy the rules.
+///
+/// Each chunk is collapsed with an RNG seeded from the world seed and its
+/// chunk coordinate, so generating a chunk next to the same neighbors always
+/// has the same result, regardless of the order other chunks were generated
+/// in.
+///
+/// The observer must be independent of coordinates (see
+/// [SetCollapseObserver::is_coordinate_independent]).
+///
+/// World cell coordinates are `(x, y)`, and chunk `(i, j)` contains cells
+/// `(i * chunk_size, j * chunk_size)` up to, but excluding,
+/// `((i + 1) * chunk_size, (j + 1) * chunk_size)`.
+pub struct ChunkedGenerator<O, S: Superposition = StateSet> {
+    rules: SetCollapseRules<O, S>,
+    chunk_size: u32,
+    margin: u32,
+    seed: u64,
+    max_attempts: u32,
+    chunks: HashMap<(i32, i32), Grid2d<S>>,
+}
+
+impl<O: SetCollapseObserver<Coordinate2d>, S: Superposition> ChunkedGenerator<O, S> {
+    /// Create a generator of chunks of `chunk_size` by `chunk_size` cells,
+    /// none of which have been generated.
+    ///
+    /// # Panics
+    ///
+    /// If `chunk_size` is zero, or the observer of `rules` depends on coordinates.
+    pub fn new(rules: SetCollapseRules<O, S>, chunk_size: u32, seed: u64) -> Self {
+        assert!(chunk_size > 0, "chunk size must be positive");
+        assert!(
+            rules.observer().is_coordinate_independent(),
+            "observer must not depend on coordinates"
+        );
+        Self {
+            rules,
+            chunk_size,
+            margin: 0,
+            seed,
+            max_attempts: 1,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Regenerate cells of already-generated chunks up to `margin` cells away
+    /// from each new chunk. Defaults to 0.
+    pub fn with_margin(mut self, margin: u32) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    /// Try up to `max_attempts` times, with different seeds, to generate each
+    /// chunk before giving up. Defaults to 1.
+    ///
+    /// # Panics
+    ///
+    /// If `max_attempts` is zero.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        assert!(max_attempts > 0, "must make at least one attempt");
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// The rules chunks are generated with.
+    pub fn rules(&self) -> &SetCollapseRules<O, S> {
+        &self.rules
+    }
+
+    /// Width and height of each chunk, in cells.
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    /// The chunk at `chunk`, if it has been generated.
+    pub fn chunk(&self, chunk: (i32, i32)) -> Option<&Grid2d<S>> {
+        self.chunks.get(&chunk)
+    }
+
+    /// Every generated chunk, in arbitrary order.
+    pub fn chunks(&self) -> impl Iterator<Item = ((i32, i32), &Grid2d<S>)> + '_ {
+        self.chunks.iter().map(|(&chunk, grid)| (chunk, grid))
+    }
+
+    /// The cell at world coordinate `cell`, if its chunk has been generated.
+    pub fn cell(&self, cell: (i64, i64)) -> Option<&S> {
+        let (chunk, coordinate) = self.locate(cell);
+        self.chunks.get(&chunk).map(|grid| &grid[coordinate])
+    }
+
+    /// Forget a generated chunk, such that it no longer constrains its
+    /// neighbors and would be generated again.
+    pub fn remove(&mut self, chunk: (i32, i32)) -> Option<Grid2d<S>> {
+        self.chunks.remove(&chunk)
+    }
+
+    /// Generate the chunk at `chunk`, constrained by the generated chunks
+    /// around it, or return it if it has already been generated.
+    ///
+    /// Returns the [Contradiction] of the final attempt, in world
+    /// coordinates, if the chunk can't be generated, leaving every chunk
+    /// unchanged.
+    pub fn generate<R: Rng + SeedableRng>(
+        &mut self,
+        chunk: (i32, i32),
+    ) -> Result<&Grid2d<S>, Contradiction<(i64, i64)>> {
+        if !self.chunks.contains_key(&chunk) {
+            self.generate_new::<R>(chunk)?;
+        }
+        Ok(&self.chunks[&chunk])
+    }
+
+    fn generate_new<R: Rng + SeedableRng>(
+        &mut self,
+        chunk: (i32, i32),
+    ) -> Result<(), Contradiction<(i64, i64)>> {
+        let chunk_size = self.chunk_size;
+        let ring = self.margin + 1;
+        let size = chunk_size + 2 * ring;
+        let origin = (
+            chunk.0 as i64 * chunk_size as i64 - ring as i64,
+            chunk.1 as i64 * chunk_size as i64 - ring as i64,
+        );
+        let world = |c: Coordinate2d| (origin.0 + c.x as i64, origin.1 + c.y as i64);
+        // Number of cells outside the chunk, or 0 within it.
+        let distance = |c: Coordinate2d| {
+            let outside = |v: u32| {
+                ring.saturating_sub(v)
+                    .max(v.saturating_sub(ring + chunk_size - 1))
+            };
+            outside(c.x).max(outside(c.y))
+        };
+
+        let initial = Grid2d::new(Coordinate2d { x: size, y: size }, |c| {
+            match self.cell(world(c)) {
+                Some(cell) if distance(c) > self.margin => cell.clone(),
+                _ => S::all(self.rules.domain()),
+            }
+        });
+        let mut attempt = 0;
+        let grid = loop {
+            let mut rng = R::seed_from_u64(chunk_seed(self.seed, chunk, attempt));
+            let mut grid = initial.clone();
+            match collapse(&mut grid, &self.rules, &mut rng, |_| {}) {
+                Ok(()) => break grid,
+                Err(contradiction) => {
+                    attempt += 1;
+                    if attempt >= self.max_attempts {
+                        let cause = match contradiction.cause {
+                            Cause::Empty(coordinate) => Cause::Empty(world(coordinate)),
+                            Cause::Count(state) => Cause::Count(state),
+                        };
+                        return Err(Contradiction {
+                            cause,
+                            observed: contradiction.observed.map(world),
+                            step: contradiction.step,
+                        });
+                    }
+                }
+            }
+        };
+
+        let mut generated = Grid2d::new(
+            Coordinate2d {
+                x: chunk_size,
+                y: chunk_size,
+            },
+            |_| S::empty(self.rules.domain()),
+        );
+        grid.visit_coordinates(|c| match distance(c) {
+            0 => {
+                generated[Coordinate2d {
+                    x: c.x - ring,
+                    y: c.y - ring,
+                }] = grid[c].clone()
+            }
+            d if d <= self.margin => {
+                let (neighbor, coordinate) = self.locate(world(c));
+                if let Some(neighbor) = self.chunks.get_mut(&neighbor) {
+                    neighbor[coordinate] = grid[c].clone();
+                }
+            }
+            _ => {}
+        });
+        self.chunks.insert(chunk, generated);
+        Ok(())
+    }
+
+    /// The chunk containing world coordinate `cell`, and its coordinate
+    /// within that chunk.
+    fn locate(&self, cell: (i64, i64)) -> ((i32, i32), Coordinate2d) {
+        let size = self.chunk_size as i64;
+        (
+            (
+                cell.0.div_euclid(size) as i32,
+                cell.1.div_euclid(size) as i32,
+            ),
+            Coordinate2d {
+                x: cell.0.rem_euclid(size) as u32,
+                y: cell.1.rem_euclid(size) as u32,
+            },
+        )
+    }
+}
+
+/// Seed for generating `chunk` of the world with `seed`.
+fn chunk_seed(seed: u64, chunk: (i32, i32), attempt: u32) -> u64 {
+    [chunk.0 as u32 as u64, chunk.1 as u32 as u64, attempt as u64]
+        .into_iter()
+        .fold(seed, |hash, value| {
+            (hash ^ value)
+                .wrapping_mul(0x9E37_79B9_7F4A_7C15)
+                .rotate_left(31)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChunkedGenerator;
+    use crate::{
+        grid_2d::{Coordinate2d, Grid2d},
+        rules::tests::coordinate_dependent,
+        sockets::tests::pipes,
+        verify::tests::assert_valid,
+        Space, StateSet,
+    };
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn chunk_seams() {
+        let chunk_size = 4;
+        for margin in [0, 2] {
+            let mut chunks = ChunkedGenerator::new(pipes::<Grid2d<StateSet>, _>(), chunk_size, 7)
+                .with_margin(margin)
+                .with_max_attempts(4);
+            // Out of order, such that some chunks are generated between
+            // others.
+            for chunk in [(0, 0), (2, 1), (1, 0), (0, 1), (1, 1), (2, 0)] {
+                chunks.generate::<StdRng>(chunk).unwrap();
+            }
+            let world = Grid2d::new(
+                Coordinate2d {
+                    x: 3 * chunk_size,
+                    y: 2 * chunk_size,
+                },
+                |c| chunks.cell((c.x as i64, c.y as i64)).unwrap().clone(),
+            );
+            assert_valid(&world, chunks.rules());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "observer must not depend on coordinates")]
+    fn rejects_coordinate_dependent_observers() {
+        ChunkedGenerator::new(coordinate_dependent::<Grid2d<StateSet>>(), 4, 0);
+    }
+}
@@ -1,38 +1,15 @@
+use crate::counts::{CountAction, CountConstraints, CountTracker};
+use crate::order::{CollapseOrder, MinimumCount};
 use crate::rules::{SetCollapseObserver, SetCollapseRules};
 use crate::space::*;
-use crate::state::StateSet;
-use rand::Rng;
-use std::collections::VecDeque;
-
-fn find_next_to_collapse<Sp: Space<StateSet>>(
-    unresoved_set: &mut Vec<Sp::Coordinate>,
-    lowest_entropy_set: &mut Vec<Sp::Coordinate>,
-    space: &Sp,
-    rng: &mut impl Rng,
-) -> Option<Sp::Coordinate> {
-    let mut lowest_entropy = u32::MAX;
-    lowest_entropy_set.clear();
-    unresoved_set.retain(|unresolved| {
-        let entropy = space[*unresolved].entropy();
-        if entropy == 0 {
-            return false;
-        }
-        if entropy > lowest_entropy {
-            return true;
-        }
-        if entropy < lowest_entropy {
-            lowest_entropy = entropy;
-            lowest_entropy_set.clear();
-        }
-        lowest_entropy_set.push(*unresolved);
-        true
-    });
-    if lowest_entropy_set.is_empty() {
-        None
-    } else {
-        Some(lowest_entropy_set[rng.gen_range(0..lowest_entropy_set.len())])
-    }
-}
+use crate::state::{State, StateDomain, StateSet, Superposition};
+use crate::support::Supports;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
 
 /// Incremental WFC progress.
 #[derive(Debug, Clone)]
@@ -43,66 +20,761 @@ pub struct Progress {
     pub total: u32,
 }
 
+/// A cell was left with no possible states, or [crate::counts::CountConstraints]
+/// can no longer be met, so the space cannot satisfy the rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contradiction<C> {
+    /// What can no longer be satisfied.
+    pub cause: Cause<C>,
+    /// The cell that was being observed when the contradiction arose, or
+    /// `None` if it arose while propagating the initial constraints.
+    pub observed: Option<C>,
+    /// Number of observations made, including the one in [Self::observed].
+    pub step: u32,
+}
+
+/// The cause of a [Contradiction].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cause<C> {
+    /// The cell was left with no possible states.
+    Empty(C),
+    /// The [crate::counts::CountConstraints] of the state can no longer be met.
+    Count(State),
+}
+
+impl<C: Debug> Display for Contradiction<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.cause {
+            Cause::Empty(coordinate) => write!(f, "contradiction at {coordinate:?}")?,
+            Cause::Count(state) => write!(f, "count of {state:?} can't be met")?,
+        }
+        if let Some(observed) = &self.observed {
+            write!(f, " after observing {observed:?}")?;
+        } else {
+            write!(f, " in initial constraints")?;
+        }
+        write!(f, " (step {})", self.step)
+    }
+}
+
+impl<C: Debug> Error for Contradiction<C> {}
+
+/// Limits for [collapse_backtracking].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backtracking {
+    /// Maximum number of most recent observations that can be undone. Older
+    /// observations become permanent.
+    pub max_depth: usize,
+    /// Maximum number of observations to undo before giving up.
+    pub max_backtracks: u32,
+}
+
+impl Default for Backtracking {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            max_backtracks: 1024,
+        }
+    }
+}
+
+/// How [Collapser] propagates the consequences of removing states.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Propagation {
+    /// When a cell changes, re-check every state of each of its neighbors
+    /// against all of their neighbors.
+    #[default]
+    Cellwise,
+    /// Count, for every cell, state and direction, the states in the neighbor
+    /// that allow it, and remove states whose count reaches zero (AC-4).
+    ///
+    /// Uses memory proportional to cells times states times directions, but
+    /// is much faster for rules with many states.
+    SupportCount,
+}
+
+/// Cells to propagate, in the order they were queued, each at most once.
+///
+/// Queues are usually short, so whether a cell is queued is checked by
+/// searching the queue, until it grows long enough (e.g. when a count
+/// constraint changes many cells) that a set of queued cells is kept instead.
+struct Queue<C> {
+    order: VecDeque<C>,
+    /// Empty unless `order` became long, in which case it has the same cells
+    /// until `order` is empty.
+    queued: HashSet<C>,
+}
+
+impl<C> Default for Queue<C> {
+    fn default() -> Self {
+        Self {
+            order: VecDeque::new(),
+            queued: HashSet::new(),
+        }
+    }
+}
+
+impl<C: Copy + Hash + Eq> Queue<C> {
+    const SEARCH_LEN: usize = 32;
+
+    /// Queue `coord`, unless it is already queued.
+    fn push(&mut self, coord: C) {
+        if self.queued.is_empty() {
+            if self.order.contains(&coord) {
+                return;
+            }
+            self.order.push_back(coord);
+            if self.order.len() > Self::SEARCH_LEN {
+                self.queued.extend(self.order.iter().copied());
+            }
+        } else if self.queued.insert(coord) {
+            self.order.push_back(coord);
+        }
+    }
+
+    fn pop(&mut self) -> Option<C> {
+        let coord = self.order.pop_front()?;
+        if !self.queued.is_empty() {
+            self.queued.remove(&coord);
+        }
+        Some(coord)
+    }
+
+    fn clear(&mut self) {
+        for coord in self.order.drain(..) {
+            if self.queued.is_empty() {
+                break;
+            }
+            self.queued.remove(&coord);
+        }
+    }
+}
+
+/// An observation that can be undone.
+struct Frame<C, S> {
+    observed: C,
+    /// States the observed cell was resolved to.
+    observation: S,
+    /// Start of the changes caused by this observation in the journal.
+    journal_start: usize,
+}
+
 /// Perform the wave function collapse algorithm on a given state-space with
 /// the provided collapse rule.
-pub fn collapse<Sp: Space<StateSet>, O: SetCollapseObserver>(
+///
+/// Returns a [Contradiction] as soon as any cell is left with no possible
+/// states, leaving `space` partially collapsed.
+pub fn collapse<Sp: Space<S>, O: SetCollapseObserver<Sp::Coordinate>, S: Superposition>(
     space: &mut Sp,
-    rule: &SetCollapseRules<O>,
+    rule: &SetCollapseRules<O, S>,
     rng: &mut impl Rng,
-    mut on_progress: impl FnMut(Progress),
-) {
-    let mut unresolved_set = Vec::new();
-    let mut to_propogate = VecDeque::new();
-    let mut lowest_entropy_set = Vec::new();
-    let mut cells = 0u32;
-    Sp::visit_coordinates(space.dimensions(), |coord| {
-        if space[coord].entropy() > 0 {
-            unresolved_set.push(coord);
-            to_propogate.push_back(coord);
-        }
-        cells += 1;
+    on_progress: impl FnMut(Progress),
+) -> Result<(), Contradiction<Sp::Coordinate>> {
+    Collapser::new(space).run(space, rule, rng, on_progress)
+}
+
+/// Like [collapse], but when an observation leads to a contradiction, undoes
+/// it, bans the observed state, and continues.
+///
+/// If backtracking can't resolve a contradiction within the limits of
+/// `backtracking`, returns the [Contradiction] caused by the observation that
+/// triggered it, rather than any encountered while backtracking.
+pub fn collapse_backtracking<
+    Sp: Space<S>,
+    O: SetCollapseObserver<Sp::Coordinate>,
+    S: Superposition,
+>(
+    space: &mut Sp,
+    rule: &SetCollapseRules<O, S>,
+    rng: &mut impl Rng,
+    backtracking: Backtracking,
+    on_progress: impl FnMut(Progress),
+) -> Result<(), Contradiction<Sp::Coordinate>> {
+    Collapser::new(space)
+        .with_backtracking(backtracking)
+        .run(space, rule, rng, on_progress)
+}
+
+/// Regenerate the cells of a collapsed `space` for which `region` returns
+/// `true`, by resetting them to every state and collapsing them again,
+/// constrained by the rest of the space, which is left unchanged.
+///
+/// Returns a [Contradiction] if the rest of the space makes this impossible,
+/// restoring the previous states of `space`.
+///
+/// # Panics
+///
+/// If a cell outside `region` isn't resolved to exactly one state.
+pub fn regenerate<Sp: Space<S>, O: SetCollapseObserver<Sp::Coordinate>, S: Superposition>(
+    space: &mut Sp,
+    rule: &SetCollapseRules<O, S>,
+    region: impl Fn(Sp::Coordinate) -> bool,
+    rng: &mut impl Rng,
+    on_progress: impl FnMut(Progress),
+) -> Result<(), Contradiction<Sp::Coordinate>> {
+    // Only the region and its neighbors can change before a contradiction.
+    let mut previous = Vec::new();
+    let mut neighbors = vec![None; Sp::DIRECTIONS.len()];
+    space.visit_coordinates(|coord| {
+        if !region(coord) {
+            assert!(
+                space[coord].entropy() == 0 && !space[coord].is_empty(),
+                "cell outside region isn't collapsed"
+            );
+        } else {
+            previous.push((coord, space[coord].clone()));
+            fill_neighbors::<Sp, S>(space, coord, &mut neighbors);
+            for neighbor in neighbors.iter().flatten() {
+                if !region(*neighbor) {
+                    previous.push((*neighbor, space[*neighbor].clone()));
+                }
+            }
+        }
     });
-    let mut neighbors = vec![None; Sp::DIRECTIONS.len()].into_boxed_slice();
-    let mut neighbor_states =
-        vec![Option::<StateSet>::None; Sp::DIRECTIONS.len()].into_boxed_slice();
-
-    run_propogation(
-        space,
-        rule,
-        &mut to_propogate,
-        &mut neighbors,
-        &mut neighbor_states,
-    );
-
-    while let Some(to_collapse) =
-        find_next_to_collapse(&mut unresolved_set, &mut lowest_entropy_set, space, rng)
-    {
-        on_progress(Progress {
-            resolved: cells - unresolved_set.len() as u32,
-            total: cells,
+    for (coord, _) in &previous {
+        if region(*coord) {
+            space[*coord] = S::all(rule.domain());
+        }
+    }
+    let result = Collapser::new(space).run(space, rule, rng, on_progress);
+    if result.is_err() {
+        for (coord, cell) in previous {
+            space[coord] = cell;
+        }
+    }
+    result
+}
+
+/// Outcome of [collapse_with_retries].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryOutcome<C> {
+    /// Number of attempts made, including the final one.
+    pub attempts: u32,
+    /// Result of the final attempt.
+    pub result: Result<(), Contradiction<C>>,
+}
+
+/// Like [collapse], but restarts from the initial `space` after a
+/// contradiction, up to `max_attempts` times in total.
+///
+/// Each attempt uses an `R` seeded from `seed` and the attempt number, so
+/// the outcome is reproducible.
+///
+/// # Panics
+///
+/// If `max_attempts` is zero.
+pub fn collapse_with_retries<
+    Sp: Space<S> + Clone,
+    O: SetCollapseObserver<Sp::Coordinate>,
+    R: Rng + SeedableRng,
+    S: Superposition,
+>(
+    space: &mut Sp,
+    rule: &SetCollapseRules<O, S>,
+    seed: u64,
+    max_attempts: u32,
+    mut on_progress: impl FnMut(Progress),
+) -> RetryOutcome<Sp::Coordinate> {
+    assert!(max_attempts > 0, "must make at least one attempt");
+    let initial = space.clone();
+    let mut attempts = 0;
+    loop {
+        let mut rng = R::seed_from_u64(
+            seed.wrapping_add((attempts as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)),
+        );
+        attempts += 1;
+        let result = Collapser::new(space).run(space, rule, &mut rng, &mut on_progress);
+        if result.is_ok() || attempts >= max_attempts {
+            return RetryOutcome { attempts, result };
+        }
+        space.clone_from(&initial);
+    }
+}
+
+/// Step-wise wave function collapse, for spreading the work of [collapse]
+/// over time or observing the space between steps.
+///
+/// Every method must be passed the same space and rules.
+pub struct Collapser<Sp: Space<S>, H = MinimumCount, S: Superposition = StateSet> {
+    order: H,
+    unresolved_set: Vec<Sp::Coordinate>,
+    to_propogate: Queue<Sp::Coordinate>,
+    neighbors: Box<[Option<Sp::Coordinate>]>,
+    neighbor_states: Box<[Option<S>]>,
+    /// `None` until the initial constraints are propagated.
+    next: Option<Option<Sp::Coordinate>>,
+    contradiction: Option<Contradiction<Sp::Coordinate>>,
+    cells: u32,
+    steps: u32,
+    backtracking: Option<Backtracking>,
+    journal: Vec<(Sp::Coordinate, S)>,
+    frames: VecDeque<Frame<Sp::Coordinate, S>>,
+    backtracks: u32,
+    propagation: Propagation,
+    supports: Option<Supports<Sp::Coordinate>>,
+    counts: Option<CountTracker<Sp::Coordinate>>,
+    /// Domain of the cells of the space, unless it has none.
+    domain: Option<StateDomain>,
+}
+
+impl<Sp: Space<S>, S: Superposition> Collapser<Sp, MinimumCount, S> {
+    /// Prepare to collapse `space`, in the [MinimumCount] order.
+    pub fn new(space: &Sp) -> Self {
+        let mut unresolved_set = Vec::new();
+        let mut to_propogate = Queue::default();
+        let mut cells = 0u32;
+        let mut empty = None;
+        let mut domain = None;
+        space.visit_coordinates(|coord| {
+            let cell = &space[coord];
+            domain.get_or_insert_with(|| cell.domain());
+            if cell.entropy() > 0 {
+                unresolved_set.push(coord);
+            } else if cell.is_empty() && empty.is_none() {
+                empty = Some(coord);
+            }
+            // Resolved cells are checked too, as their neighbors may have
+            // been resolved to states they don't allow.
+            to_propogate.push(coord);
+            cells += 1;
         });
-        to_propogate.clear();
-        fill_neighbors(&*space, to_collapse, &mut neighbors);
-        for i in 0..Sp::DIRECTIONS.len() {
-            neighbor_states[i] = neighbors[i].map(|coord| space[coord].clone());
+        Self {
+            order: MinimumCount::default(),
+            unresolved_set,
+            to_propogate,
+            neighbors: vec![None; Sp::DIRECTIONS.len()].into_boxed_slice(),
+            neighbor_states: vec![None; Sp::DIRECTIONS.len()].into_boxed_slice(),
+            next: None,
+            contradiction: empty.map(|coordinate| Contradiction {
+                cause: Cause::Empty(coordinate),
+                observed: None,
+                step: 0,
+            }),
+            cells,
+            steps: 0,
+            backtracking: None,
+            journal: Vec::new(),
+            frames: VecDeque::new(),
+            backtracks: 0,
+            propagation: Propagation::Cellwise,
+            supports: None,
+            counts: None,
+            domain,
         }
-        rule.observe(&mut space[to_collapse], &neighbor_states[..], rng);
+    }
+}
+
+impl<Sp: Space<S>, H: CollapseOrder<Sp, S>, S: Superposition> Collapser<Sp, H, S> {
+    /// Choose cells to observe with `order` instead.
+    pub fn with_order<H2: CollapseOrder<Sp, S>>(self, order: H2) -> Collapser<Sp, H2, S> {
+        Collapser {
+            order,
+            unresolved_set: self.unresolved_set,
+            to_propogate: self.to_propogate,
+            neighbors: self.neighbors,
+            neighbor_states: self.neighbor_states,
+            next: self.next,
+            contradiction: self.contradiction,
+            cells: self.cells,
+            steps: self.steps,
+            backtracking: self.backtracking,
+            journal: self.journal,
+            frames: self.frames,
+            backtracks: self.backtracks,
+            propagation: self.propagation,
+            supports: self.supports,
+            counts: self.counts,
+            domain: self.domain,
+        }
+    }
+
+    /// Undo observations that lead to contradictions, within the limits of
+    /// `backtracking` (see [collapse_backtracking]).
+    pub fn with_backtracking(mut self, backtracking: Backtracking) -> Self {
+        self.backtracking = Some(backtracking);
+        self
+    }
+
+    /// Propagate constraints with `propagation` instead of
+    /// [Propagation::Cellwise].
+    pub fn with_propagation(mut self, propagation: Propagation) -> Self {
+        self.propagation = propagation;
+        self
+    }
+
+    /// Enforce `counts`, reporting a [Contradiction] once they can't be met.
+    ///
+    /// # Panics
+    ///
+    /// If `counts` constrain a state outside the domain of the space.
+    pub fn with_counts(mut self, counts: CountConstraints) -> Self {
+        if let Some(domain) = self.domain {
+            if let Some(state) = counts.states().find(|state| state.0 >= domain.len()) {
+                panic!(
+                    "count constraint on {state:?} outside domain of {} states",
+                    domain.len()
+                );
+            }
+        }
+        self.counts = Some(CountTracker::new(counts));
+        self
+    }
+
+    /// Whether the space is fully collapsed, or a [Contradiction] was
+    /// encountered.
+    pub fn is_done(&self) -> bool {
+        self.contradiction.is_some() || self.next == Some(None)
+    }
+
+    /// Number of observations made so far.
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    /// Current progress.
+    pub fn progress(&self) -> Progress {
+        Progress {
+            resolved: self.cells - self.unresolved_set.len() as u32,
+            total: self.cells,
+        }
+    }
+
+    /// Propagate the initial constraints if this is the first step, or else
+    /// observe one cell and propagate the consequences.
+    ///
+    /// Does nothing if [Self::is_done]. Once a [Contradiction] is returned,
+    /// it will be returned by every subsequent step.
+    pub fn step<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        space: &mut Sp,
+        rule: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+    ) -> Result<(), Contradiction<Sp::Coordinate>> {
+        if let Some(contradiction) = &self.contradiction {
+            return Err(contradiction.clone());
+        }
+        let Some(next) = self.next else {
+            if self.propagation == Propagation::SupportCount {
+                let mut supports = Supports::new(&*space, rule);
+                let order = &mut self.order;
+                let banned = supports
+                    .ban_unsupported(space, |coord, state| order.removed(coord, state, rule));
+                self.supports = Some(supports);
+                self.to_propogate.clear();
+                if let Err(coordinate) = banned {
+                    return Err(self.fail(Cause::Empty(coordinate), None));
+                }
+            }
+            if let Some(counts) = &mut self.counts {
+                counts.reset(&*space);
+            }
+            if let Err(cause) = self.propagate(space, rule, false) {
+                return Err(self.fail(cause, None));
+            }
+            self.next = Some(
+                self.order
+                    .select(&mut self.unresolved_set, space, rule, rng),
+            );
+            return Ok(());
+        };
+        let Some(to_collapse) = next else {
+            return Ok(());
+        };
+        self.steps += 1;
+        fill_neighbors(&*space, to_collapse, &mut self.neighbors);
         for i in 0..Sp::DIRECTIONS.len() {
-            if let Some(neighbor_coord) = neighbors[i] {
-                to_propogate.push_back(neighbor_coord);
+            self.neighbor_states[i] = self.neighbors[i].map(|coord| space[coord].clone());
+        }
+        let mut observed = space[to_collapse].clone();
+        rule.observe(
+            &*space,
+            to_collapse,
+            &mut observed,
+            &self.neighbor_states[..],
+            rng,
+        );
+        let journaling = self.begin_frame(space, to_collapse, &observed);
+        let before = std::mem::replace(&mut space[to_collapse], observed);
+        for state in before.iter() {
+            if !space[to_collapse].has(state) {
+                self.removed(to_collapse, state, rule);
+            }
+        }
+        self.propogate_neighbors_of(&*space, to_collapse);
+        if let Err(cause) = self.propagate(space, rule, journaling) {
+            if !self.backtrack(space, rule) {
+                return Err(self.fail(cause, Some(to_collapse)));
             }
+            self.unresolved_set.clear();
+            space.visit_coordinates(|coord| {
+                if space[coord].entropy() > 0 {
+                    self.unresolved_set.push(coord);
+                }
+            });
         }
-        run_propogation(
-            space,
-            rule,
-            &mut to_propogate,
-            &mut neighbors,
-            &mut neighbor_states,
+        self.next = Some(
+            self.order
+                .select(&mut self.unresolved_set, space, rule, rng),
         );
+        Ok(())
+    }
+
+    /// Step until [Self::is_done] or `duration` has elapsed, whichever comes
+    /// first. Always makes at least one step.
+    pub fn run_for<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        space: &mut Sp,
+        rule: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+        duration: Duration,
+    ) -> Result<(), Contradiction<Sp::Coordinate>> {
+        let start = Instant::now();
+        loop {
+            self.step(space, rule, rng)?;
+            if self.is_done() || start.elapsed() >= duration {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Step until [Self::is_done].
+    pub fn run<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        space: &mut Sp,
+        rule: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+        mut on_progress: impl FnMut(Progress),
+    ) -> Result<(), Contradiction<Sp::Coordinate>> {
+        loop {
+            self.step(space, rule, rng)?;
+            if self.is_done() {
+                return Ok(());
+            }
+            on_progress(self.progress());
+        }
+    }
+
+    fn fail(
+        &mut self,
+        cause: Cause<Sp::Coordinate>,
+        observed: Option<Sp::Coordinate>,
+    ) -> Contradiction<Sp::Coordinate> {
+        let contradiction = Contradiction {
+            cause,
+            observed,
+            step: self.steps,
+        };
+        self.contradiction = Some(contradiction.clone());
+        contradiction
+    }
+
+    /// Returns whether changes should be journaled.
+    fn begin_frame(&mut self, space: &Sp, to_collapse: Sp::Coordinate, observed: &S) -> bool {
+        let Some(backtracking) = &self.backtracking else {
+            return false;
+        };
+        if self.frames.is_empty() {
+            self.journal.clear();
+        } else if self.frames.len() >= backtracking.max_depth {
+            self.frames.pop_front();
+            let forget = self
+                .frames
+                .front()
+                .map_or(self.journal.len(), |f| f.journal_start);
+            self.journal.drain(..forget);
+            for frame in &mut self.frames {
+                frame.journal_start -= forget;
+            }
+        }
+        if backtracking.max_depth > 0 {
+            self.frames.push_back(Frame {
+                observed: to_collapse,
+                observation: observed.clone(),
+                journal_start: self.journal.len(),
+            });
+            self.journal.push((to_collapse, space[to_collapse].clone()));
+        }
+        true
+    }
+
+    /// Undo observations until the space is consistent again, returning
+    /// `false` if that isn't possible.
+    fn backtrack<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        space: &mut Sp,
+        rule: &SetCollapseRules<O, S>,
+    ) -> bool {
+        let Some(backtracking) = self.backtracking else {
+            return false;
+        };
+        loop {
+            if self.backtracks >= backtracking.max_backtracks {
+                return false;
+            }
+            let Some(frame) = self.frames.pop_back() else {
+                return false;
+            };
+            self.backtracks += 1;
+            for (coord, states) in self.journal.drain(frame.journal_start..).rev() {
+                for state in states.iter() {
+                    if !space[coord].has(state) {
+                        self.order.restored(coord, state, rule);
+                        if let Some(supports) = &mut self.supports {
+                            supports.restore(coord, state);
+                        }
+                        if let Some(counts) = &mut self.counts {
+                            counts.restored(coord, state);
+                        }
+                    }
+                }
+                space[coord] = states;
+            }
+
+            // The ban is a consequence of earlier observations, so it is
+            // journaled as part of the previous frame.
+            self.journal
+                .push((frame.observed, space[frame.observed].clone()));
+            space[frame.observed].remove_all(&frame.observation);
+            for state in frame.observation.iter() {
+                self.removed(frame.observed, state, rule);
+            }
+            if space[frame.observed].is_empty() {
+                continue;
+            }
+            self.propogate_neighbors_of(&*space, frame.observed);
+            if self.propagate(space, rule, true).is_ok() {
+                return true;
+            }
+        }
+    }
+
+    /// Propagate constraints, and then enforce [Self::with_counts] and
+    /// propagate the consequences until the space no longer changes.
+    fn propagate<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        space: &mut Sp,
+        rule: &SetCollapseRules<O, S>,
+        journaling: bool,
+    ) -> Result<(), Cause<Sp::Coordinate>> {
+        self.run_propogation(space, rule, journaling)
+            .map_err(Cause::Empty)?;
+        loop {
+            let Some(counts) = &mut self.counts else {
+                return Ok(());
+            };
+            let (state, keep) = match counts.check(&*space) {
+                None => return Ok(()),
+                Some(CountAction::Violated(state)) => return Err(Cause::Count(state)),
+                Some(CountAction::Ban(state)) => (state, false),
+                Some(CountAction::Force(state)) => (state, true),
+            };
+            for coord in counts.unresolved(&*space, state) {
+                let before = space[coord].clone();
+                for removed in before.iter().filter(|&s| (s == state) != keep) {
+                    space[coord].remove(removed);
+                    self.removed(coord, removed, rule);
+                }
+                if journaling {
+                    self.journal.push((coord, before));
+                }
+                if self.supports.is_none() {
+                    // The cell may now be resolved, and is checked against its
+                    // neighbors, which may have been resolved at the same time.
+                    self.to_propogate.push(coord);
+                    fill_neighbors(&*space, coord, &mut self.neighbors);
+                    for neighbor in self.neighbors.iter().flatten() {
+                        if space[*neighbor].entropy() != 0 {
+                            self.to_propogate.push(*neighbor);
+                        }
+                    }
+                }
+            }
+            self.run_propogation(space, rule, journaling)
+                .map_err(Cause::Empty)?;
+        }
+    }
+
+    fn run_propogation<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        space: &mut Sp,
+        rule: &SetCollapseRules<O, S>,
+        journaling: bool,
+    ) -> Result<(), Sp::Coordinate> {
+        let (order, counts) = (&mut self.order, &mut self.counts);
+        let mut removed = |coord, state| {
+            order.removed(coord, state, rule);
+            if let Some(counts) = counts {
+                counts.removed(coord, state);
+            }
+        };
+        if let Some(supports) = &mut self.supports {
+            return supports.propagate(space, journaling.then_some(&mut self.journal), removed);
+        }
+        while let Some(propogating) = self.to_propogate.pop() {
+            // Resolved cells are checked too, as neighboring cells may have
+            // been resolved at once (initially, or by a count constraint) to
+            // states that aren't allowed together.
+            let entropy_before = space[propogating].entropy();
+            fill_neighbors(&*space, propogating, &mut self.neighbors);
+            for i in 0..Sp::DIRECTIONS.len() {
+                self.neighbor_states[i] = self.neighbors[i].map(|coord| space[coord].clone());
+            }
+            let before = journaling.then(|| space[propogating].clone());
+            rule.collapse(&mut space[propogating], &self.neighbor_states, |state| {
+                removed(propogating, state)
+            });
+            if let Some(before) = before {
+                if space[propogating] != before {
+                    self.journal.push((propogating, before));
+                }
+            }
+            if space[propogating].is_empty() {
+                self.to_propogate.clear();
+                return Err(propogating);
+            }
+            let entropy_after = space[propogating].entropy();
+
+            if entropy_after < entropy_before {
+                for neighbor in self.neighbors.iter().flatten() {
+                    if space[*neighbor].entropy() != 0 {
+                        self.to_propogate.push(*neighbor);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `state` was removed from the cell at `coord` other than by propagation.
+    fn removed<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        coord: Sp::Coordinate,
+        state: State,
+        rule: &SetCollapseRules<O, S>,
+    ) {
+        self.order.removed(coord, state, rule);
+        if let Some(supports) = &mut self.supports {
+            supports.ban(coord, state);
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.removed(coord, state);
+        }
+    }
+
+    /// Queue the neighbors of `coord` for [Propagation::Cellwise].
+    fn propogate_neighbors_of(&mut self, space: &Sp, coord: Sp::Coordinate) {
+        if self.supports.is_some() {
+            return;
+        }
+        fill_neighbors(space, coord, &mut self.neighbors);
+        self.to_propogate.clear();
+        for neighbor_coord in self.neighbors.iter().flatten() {
+            self.to_propogate.push(*neighbor_coord);
+        }
     }
 }
 
-fn fill_neighbors<Sp: Space<StateSet>>(
+fn fill_neighbors<Sp: Space<S>, S>(
     space: &Sp,
     coord: Sp::Coordinate,
     directions: &mut [Option<Sp::Coordinate>],
@@ -112,33 +784,320 @@ fn fill_neighbors<Sp: Space<StateSet>>(
     }
 }
 
-fn run_propogation<Sp: Space<StateSet>, O: SetCollapseObserver>(
-    space: &mut Sp,
-    rule: &SetCollapseRules<O>,
-    to_propogate: &mut VecDeque<Sp::Coordinate>,
-    neighbors: &mut [Option<Sp::Coordinate>],
-    neighbor_states: &mut [Option<StateSet>],
-) {
-    while let Some(propogating) = to_propogate.pop_front() {
-        let entropy_before = space[propogating].entropy();
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        collapse, collapse_backtracking, collapse_with_retries, regenerate, Backtracking, Cause,
+        Collapser, Contradiction, Propagation, Queue,
+    };
+    use crate::{
+        counts::tests::random_rule,
+        grid_2d::{Axis2d, Coordinate2d, Direction2d, Grid2d},
+        order::Scanline,
+        rules::{SetCollapseRules, SetCollapseRulesBuilder, UniformSetCollapseObserver},
+        sockets::tests::pipes,
+        verify::tests::assert_valid,
+        Space, State, StateDomain, StateSet,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::{fmt::Debug, time::Duration};
 
-        if entropy_before != 0 {
-            fill_neighbors(&*space, propogating, neighbors);
-            for i in 0..Sp::DIRECTIONS.len() {
-                neighbor_states[i] = neighbors[i].map(|coord| space[coord].clone());
+    /// Two colors, where neighbors must have different colors.
+    pub(crate) fn two_colors() -> SetCollapseRules<UniformSetCollapseObserver> {
+        let domain = StateDomain::new(2);
+        let mut builder =
+            SetCollapseRulesBuilder::<Grid2d<StateSet>, _>::new(domain, UniformSetCollapseObserver);
+        for state in domain.states() {
+            let mut other = domain.all();
+            other.remove(state);
+            builder = builder.allow(state, &[(Direction2d::Right, other)]);
+        }
+        builder.build()
+    }
+
+    /// Whether resolving `first`, in a fresh `Sp` of `dimensions` configured
+    /// by `periodic`, to a state that can't be next to itself in `direction`
+    /// constrains `last` when propagating the initial constraints.
+    pub(crate) fn constrains<Sp: Space<StateSet>>(
+        dimensions: Sp::Coordinate,
+        periodic: impl FnOnce(Sp) -> Sp,
+        direction: Sp::Direction,
+        first: Sp::Coordinate,
+        last: Sp::Coordinate,
+    ) -> bool
+    where
+        Sp::Coordinate: Debug,
+    {
+        let domain = StateDomain::new(2);
+        let mut builder = SetCollapseRulesBuilder::<Sp, _>::new(domain, UniformSetCollapseObserver);
+        for &d in Sp::DIRECTIONS {
+            builder = builder.allow(State::nth(0), &[(d, domain.all())]);
+            if d != direction && d != -direction {
+                builder = builder.allow(State::nth(1), &[(d, domain.all())]);
             }
-            rule.collapse(&mut space[propogating], neighbor_states);
-            let entropy_after = space[propogating].entropy();
+        }
+        let rule = builder.build();
+        let mut space = periodic(Sp::new(dimensions, |_| domain.all()));
+        space[first] = domain.with_states(&[State::nth(1)]);
+        let mut collapser = Collapser::new(&space);
+        collapser
+            .step(&mut space, &rule, &mut StdRng::seed_from_u64(0))
+            .unwrap();
+        space[last] != domain.all()
+    }
 
-            if entropy_after < entropy_before {
-                for i in 0..Sp::DIRECTIONS.len() {
-                    if let Some(neighbor) = neighbors[i] {
-                        if space[neighbor].entropy() != 0 && !to_propogate.contains(&neighbor) {
-                            to_propogate.push_back(neighbor);
-                        }
+    #[test]
+    fn contradiction_reports_where() {
+        let rule = two_colors();
+        let at = |x| Coordinate2d { x, y: 0 };
+        let one_color = rule.domain().with_states(&[State::nth(0)]);
+        let mut space = Grid2d::new(Coordinate2d { x: 3, y: 1 }, |c| {
+            if c.x < 2 {
+                one_color.clone()
+            } else {
+                rule.domain().all()
+            }
+        });
+        let contradiction = collapse(&mut space, &rule, &mut StdRng::seed_from_u64(0), |_| {});
+        assert_eq!(
+            contradiction,
+            Err(Contradiction {
+                cause: Cause::Empty(at(0)),
+                observed: None,
+                step: 0,
+            })
+        );
+        assert_eq!(
+            contradiction.unwrap_err().to_string(),
+            "contradiction at Coordinate2d { x: 0, y: 0 } in initial constraints (step 0)"
+        );
+
+        // A ring of three can't be colored, but that isn't found until the
+        // first cell is observed.
+        let mut space = Grid2d::new(Coordinate2d { x: 3, y: 1 }, |_| rule.domain().all())
+            .with_periodic(Axis2d::X, true);
+        let mut collapser = Collapser::new(&space).with_order(Scanline);
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(collapser.step(&mut space, &rule, &mut rng), Ok(()));
+        let contradiction = Contradiction {
+            cause: Cause::Empty(at(2)),
+            observed: Some(at(0)),
+            step: 1,
+        };
+        assert_eq!(
+            collapser.step(&mut space, &rule, &mut rng),
+            Err(contradiction.clone())
+        );
+        assert_eq!(
+            contradiction.to_string(),
+            "contradiction at Coordinate2d { x: 2, y: 0 } after observing \
+             Coordinate2d { x: 0, y: 0 } (step 1)"
+        );
+        assert!(collapser.is_done());
+        assert_eq!(
+            collapser.step(&mut space, &rule, &mut rng),
+            Err(contradiction)
+        );
+    }
+
+    #[test]
+    fn queue_dedups() {
+        let mut queue = Queue::default();
+        for len in [4, 100] {
+            for _ in 0..2 {
+                (0..len).for_each(|i| queue.push(i));
+            }
+            assert_eq!(queue.pop(), Some(0));
+            queue.push(0);
+            assert!((1..len).chain([0]).eq(std::iter::from_fn(|| queue.pop())));
+            (0..len).for_each(|i| queue.push(i));
+            queue.clear();
+            assert!(queue.queued.is_empty());
+            queue.push(0);
+            assert_eq!((queue.pop(), queue.pop()), (Some(0), None));
+        }
+    }
+
+    #[test]
+    fn backtracking_resolves_contradictions() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut backtracked = 0;
+        for _ in 0..32 {
+            let rule = random_rule(&mut rng);
+            let seed = rng.gen();
+            let new_space = || Grid2d::new(Coordinate2d { x: 8, y: 8 }, |_| rule.domain().all());
+            let mut space = new_space();
+            let mut rng = StdRng::seed_from_u64(seed);
+            let Err(contradiction) = collapse(&mut space, &rule, &mut rng, |_| {}) else {
+                continue;
+            };
+
+            // Giving up straight away returns the same contradiction.
+            let mut space = new_space();
+            let mut rng = StdRng::seed_from_u64(seed);
+            let no_backtracks = Backtracking {
+                max_backtracks: 0,
+                ..Backtracking::default()
+            };
+            let result = collapse_backtracking(&mut space, &rule, &mut rng, no_backtracks, |_| {});
+            assert_eq!(result, Err(contradiction));
+
+            let mut space = new_space();
+            let mut collapser = Collapser::new(&space).with_backtracking(Backtracking::default());
+            match collapser.run(&mut space, &rule, &mut StdRng::seed_from_u64(seed), |_| {}) {
+                Ok(()) => {
+                    assert!(collapser.backtracks > 0);
+                    assert_valid(&space, &rule);
+                    backtracked += 1;
+                }
+                Err(contradiction) => {
+                    // Blamed on the observation that triggered backtracking.
+                    assert!(contradiction.observed.is_some());
+                    assert_eq!(contradiction.step, collapser.steps());
+                }
+            }
+        }
+        assert!(backtracked > 0);
+    }
+
+    #[test]
+    fn backtracking_bans_observation() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut backtracked = 0;
+        for _ in 0..32 {
+            let rule = random_rule(&mut rng);
+            let seed = rng.gen();
+            for propagation in [Propagation::Cellwise, Propagation::SupportCount] {
+                let mut space = Grid2d::new(Coordinate2d { x: 8, y: 8 }, |_| rule.domain().all());
+                let mut collapser = Collapser::new(&space)
+                    .with_propagation(propagation)
+                    .with_backtracking(Backtracking::default());
+                let mut rng = StdRng::seed_from_u64(seed);
+                while !collapser.is_done() {
+                    let (before, backtracks) = (space.clone(), collapser.backtracks);
+                    let observed = collapser.next.flatten();
+                    let _ = collapser.step(&mut space, &rule, &mut rng);
+                    // Even if the contradiction emptied the observed cell.
+                    if let (Some(observed), 1) = (observed, collapser.backtracks - backtracks) {
+                        assert_ne!(space[observed], before[observed], "{propagation:?}");
+                        backtracked += 1;
                     }
                 }
             }
         }
+        assert!(backtracked > 0);
+    }
+
+    #[test]
+    fn regenerate_seams() {
+        let rule = pipes::<Grid2d<StateSet>, _>();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut space = Grid2d::new(Coordinate2d { x: 8, y: 8 }, |_| rule.domain().all());
+        collapse(&mut space, &rule, &mut rng, |_| {}).unwrap();
+        let region = |c: Coordinate2d| (2..6).contains(&c.x) && (3..5).contains(&c.y);
+        for _ in 0..4 {
+            let before = space.clone();
+            regenerate(&mut space, &rule, region, &mut rng, |_| {}).unwrap();
+            assert_valid(&space, &rule);
+            space.visit_coordinates(|c| {
+                if !region(c) {
+                    assert_eq!(space[c], before[c], "{c:?} outside region");
+                }
+            });
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "cell outside region isn't collapsed")]
+    fn regenerate_requires_collapsed() {
+        let rule = pipes::<Grid2d<StateSet>, _>();
+        let mut space = Grid2d::new(Coordinate2d { x: 4, y: 4 }, |_| rule.domain().all());
+        let region = |c: Coordinate2d| c.x < 2;
+        let _ = regenerate(
+            &mut space,
+            &rule,
+            region,
+            &mut StdRng::seed_from_u64(0),
+            |_| {},
+        );
+    }
+
+    #[test]
+    fn retries_reseed_each_attempt() {
+        // A ring of three can never be colored.
+        let rule = two_colors();
+        let mut space = Grid2d::new(Coordinate2d { x: 3, y: 1 }, |_| rule.domain().all())
+            .with_periodic(Axis2d::X, true);
+        let outcome = collapse_with_retries::<_, _, StdRng, _>(&mut space, &rule, 0, 3, |_| {});
+        assert_eq!(outcome.attempts, 3);
+        assert!(outcome.result.is_err());
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut retried = 0;
+        for _ in 0..32 {
+            let rule = random_rule(&mut rng);
+            let seed = rng.gen();
+            let new_space = || Grid2d::new(Coordinate2d { x: 8, y: 8 }, |_| rule.domain().all());
+            let mut space = new_space();
+            let outcome =
+                collapse_with_retries::<_, _, StdRng, _>(&mut space, &rule, seed, 8, |_| {});
+
+            // The first attempt is seeded with `seed`.
+            let mut first = new_space();
+            let result = collapse(&mut first, &rule, &mut StdRng::seed_from_u64(seed), |_| {});
+            assert_eq!(outcome.attempts == 1, result.is_ok());
+            if result.is_ok() {
+                assert!(space == first);
+            }
+
+            let mut again = new_space();
+            let again_outcome =
+                collapse_with_retries::<_, _, StdRng, _>(&mut again, &rule, seed, 8, |_| {});
+            assert_eq!(again_outcome, outcome);
+            assert!(again == space);
+
+            if outcome.attempts > 1 && outcome.result.is_ok() {
+                assert_valid(&space, &rule);
+                retried += 1;
+            }
+        }
+        assert!(retried > 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "must make at least one attempt")]
+    fn retries_require_an_attempt() {
+        let rule = two_colors();
+        let mut space = Grid2d::new(Coordinate2d { x: 2, y: 1 }, |_| rule.domain().all());
+        collapse_with_retries::<_, _, StdRng, _>(&mut space, &rule, 0, 0, |_| {});
+    }
+
+    #[test]
+    fn run_for_resumes() {
+        let rule = pipes::<Grid2d<StateSet>, _>();
+        let new_space = || Grid2d::new(Coordinate2d { x: 8, y: 6 }, |_| rule.domain().all());
+        let mut expected = new_space();
+        collapse(&mut expected, &rule, &mut StdRng::seed_from_u64(0), |_| {}).unwrap();
+
+        let mut space = new_space();
+        let mut collapser = Collapser::new(&space);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut resolved = 0;
+        while !collapser.is_done() {
+            let steps = collapser.steps();
+            // Makes exactly one step, which may only propagate the initial
+            // constraints.
+            collapser
+                .run_for(&mut space, &rule, &mut rng, Duration::ZERO)
+                .unwrap();
+            assert!(collapser.steps() - steps <= 1);
+            assert!(collapser.progress().resolved >= resolved);
+            resolved = collapser.progress().resolved;
+        }
+        assert!(space == expected);
+        assert_eq!(resolved, 8 * 6);
+
+        collapser.step(&mut space, &rule, &mut rng).unwrap();
+        assert!(space == expected);
     }
 }
@@ -0,0 +1,461 @@
+//! Global constraints on the number of cells of each state.
+
+use crate::{
+    state::{State, Superposition},
+    Space,
+};
+use std::{collections::HashMap, hash::Hash};
+
+/// Minimum and maximum numbers of cells of some states, such as "exactly one
+/// exit" or "at most 10% water", for [crate::Collapser::with_counts].
+///
+/// Once a state reaches its maximum, it is removed from every other cell, and
+/// once it can only just reach its minimum, every cell that could be that
+/// state is forced to be. If a constraint can no longer be met, collapse
+/// fails with a [crate::Contradiction].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CountConstraints {
+    /// State, minimum, and maximum.
+    limits: Vec<(State, u32, u32)>,
+}
+
+/// Number of cells of a state in a space.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StateCount {
+    /// Cells resolved to the state.
+    pub definite: u32,
+    /// Cells that are, or could still be, the state.
+    pub possible: u32,
+}
+
+/// [CountConstraints] with [StateCount]s that are updated as states are
+/// removed and restored (see [crate::order::CollapseOrder::removed]), such that
+/// only the states whose counts changed need to be checked.
+pub(crate) struct CountTracker<C> {
+    constraints: CountConstraints,
+    /// Index of each state in `constraints.limits`, if it has limits.
+    limit_index: Vec<Option<usize>>,
+    /// Indexed like `constraints.limits`.
+    counts: Vec<StateCount>,
+    /// Indexed like `constraints.limits`. Whether the limits need checking.
+    changed: Vec<bool>,
+    /// Indexed like `constraints.limits`. Cells that may have the state,
+    /// which is a superset of those that do.
+    cells: Vec<Vec<C>>,
+    /// Limit index of each cell resolved to a state with limits.
+    resolved: HashMap<C, usize>,
+    /// Cells that changed since they were last checked for being resolved.
+    dirty: Vec<C>,
+}
+
+/// What must be done to a space to satisfy [CountConstraints].
+pub(crate) enum CountAction {
+    /// Remove the state from every unresolved cell.
+    Ban(State),
+    /// Resolve every unresolved cell that could be the state to it.
+    Force(State),
+    /// The constraint on the state can't be met.
+    Violated(State),
+}
+
+impl CountConstraints {
+    /// Create constraints that allow any number of cells of every state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require at least `min` cells of `state`.
+    pub fn with_min(mut self, state: State, min: u32) -> Self {
+        self.limit(state).1 = min;
+        self
+    }
+
+    /// Allow at most `max` cells of `state`.
+    pub fn with_max(mut self, state: State, max: u32) -> Self {
+        self.limit(state).2 = max;
+        self
+    }
+
+    /// Require exactly `count` cells of `state`.
+    pub fn with_exactly(self, state: State, count: u32) -> Self {
+        self.with_min(state, count).with_max(state, count)
+    }
+
+    /// Minimum and maximum number of cells of `state`.
+    pub fn limits(&self, state: State) -> (u32, u32) {
+        self.limits
+            .iter()
+            .find(|(s, _, _)| *s == state)
+            .map_or((0, u32::MAX), |&(_, min, max)| (min, max))
+    }
+
+    /// Every state with limits.
+    pub(crate) fn states(&self) -> impl Iterator<Item = State> + '_ {
+        self.limits.iter().map(|&(state, _, _)| state)
+    }
+
+    fn limit(&mut self, state: State) -> &mut (State, u32, u32) {
+        let index = match self.limits.iter().position(|(s, _, _)| *s == state) {
+            Some(index) => index,
+            None => {
+                self.limits.push((state, 0, u32::MAX));
+                self.limits.len() - 1
+            }
+        };
+        &mut self.limits[index]
+    }
+}
+
+/// Count the cells of `state` in `space`.
+pub fn count<Sp: Space<S>, S: Superposition>(space: &Sp, state: State) -> StateCount {
+    let mut count = StateCount::default();
+    space.visit_coordinates(|coordinate| {
+        let cell = &space[coordinate];
+        if cell.has(state) {
+            count.possible += 1;
+            if cell.entropy() == 0 {
+                count.definite += 1;
+            }
+        }
+    });
+    count
+}
+
+impl<C: Copy + Hash + Ord> CountTracker<C> {
+    /// Track `constraints`, which must be [Self::reset] before use.
+    pub(crate) fn new(constraints: CountConstraints) -> Self {
+        let mut limit_index = Vec::new();
+        for (i, &(state, _, _)) in constraints.limits.iter().enumerate() {
+            let state = state.0 as usize;
+            if state >= limit_index.len() {
+                limit_index.resize(state + 1, None);
+            }
+            limit_index[state] = Some(i);
+        }
+        let len = constraints.limits.len();
+        Self {
+            constraints,
+            limit_index,
+            counts: vec![StateCount::default(); len],
+            changed: vec![true; len],
+            cells: vec![Vec::new(); len],
+            resolved: HashMap::new(),
+            dirty: Vec::new(),
+        }
+    }
+
+    /// Count every state with limits in `space`, from scratch.
+    pub(crate) fn reset<Sp: Space<S, Coordinate = C>, S: Superposition>(&mut self, space: &Sp) {
+        self.counts.fill(StateCount::default());
+        self.changed.fill(true);
+        self.cells.iter_mut().for_each(Vec::clear);
+        self.resolved.clear();
+        self.dirty.clear();
+        space.visit_coordinates(|coordinate| {
+            let cell = &space[coordinate];
+            for state in cell.iter() {
+                let Some(i) = self.limit(state) else {
+                    continue;
+                };
+                self.counts[i].possible += 1;
+                self.cells[i].push(coordinate);
+                if cell.entropy() == 0 {
+                    self.counts[i].definite += 1;
+                    self.resolved.insert(coordinate, i);
+                }
+            }
+        });
+    }
+
+    /// `state` was removed from the cell at `coordinate`.
+    pub(crate) fn removed(&mut self, coordinate: C, state: State) {
+        if let Some(i) = self.limit(state) {
+            self.counts[i].possible -= 1;
+            self.changed[i] = true;
+        }
+        self.touch(coordinate);
+    }
+
+    /// `state` was restored to the cell at `coordinate`.
+    pub(crate) fn restored(&mut self, coordinate: C, state: State) {
+        if let Some(i) = self.limit(state) {
+            self.counts[i].possible += 1;
+            self.changed[i] = true;
+            self.cells[i].push(coordinate);
+        }
+        self.touch(coordinate);
+    }
+
+    /// The first action needed to satisfy the constraints in `space`, or
+    /// `None` if no state is at its limit.
+    ///
+    /// Only states whose counts changed since they were last found to be
+    /// within their limits are checked.
+    pub(crate) fn check<Sp: Space<S, Coordinate = C>, S: Superposition>(
+        &mut self,
+        space: &Sp,
+    ) -> Option<CountAction> {
+        self.update_resolved(space);
+        for (i, &(state, min, max)) in self.constraints.limits.iter().enumerate() {
+            if !self.changed[i] {
+                continue;
+            }
+            let count = self.counts[i];
+            let unresolved = count.possible > count.definite;
+            if count.definite > max || count.possible < min {
+                return Some(CountAction::Violated(state));
+            }
+            if unresolved && count.definite == max {
+                return Some(CountAction::Ban(state));
+            }
+            if unresolved && count.possible == min {
+                return Some(CountAction::Force(state));
+            }
+            self.changed[i] = false;
+        }
+        None
+    }
+
+    /// Unresolved cells in `space` that could be `state`, which must have
+    /// limits.
+    pub(crate) fn unresolved<Sp: Space<S, Coordinate = C>, S: Superposition>(
+        &mut self,
+        space: &Sp,
+        state: State,
+    ) -> Vec<C> {
+        let i = self.limit(state).unwrap();
+        let cells = &mut self.cells[i];
+        cells.retain(|&coordinate| space[coordinate].has(state));
+        cells.sort_unstable();
+        cells.dedup();
+        cells
+            .iter()
+            .copied()
+            .filter(|&coordinate| space[coordinate].entropy() > 0)
+            .collect()
+    }
+
+    fn limit(&self, state: State) -> Option<usize> {
+        self.limit_index.get(state.0 as usize).copied().flatten()
+    }
+
+    fn touch(&mut self, coordinate: C) {
+        // States are often removed from the same cell in a row.
+        if self.dirty.last() != Some(&coordinate) {
+            self.dirty.push(coordinate);
+        }
+    }
+
+    /// Update definite counts for the cells that changed.
+    fn update_resolved<Sp: Space<S, Coordinate = C>, S: Superposition>(&mut self, space: &Sp) {
+        let mut dirty = std::mem::take(&mut self.dirty);
+        for coordinate in dirty.drain(..) {
+            let cell = &space[coordinate];
+            let now = if cell.entropy() == 0 {
+                cell.iter().next().and_then(|state| self.limit(state))
+            } else {
+                None
+            };
+            let before = match now {
+                Some(i) => self.resolved.insert(coordinate, i),
+                None => self.resolved.remove(&coordinate),
+            };
+            if before != now {
+                if let Some(i) = before {
+                    self.counts[i].definite -= 1;
+                    self.changed[i] = true;
+                }
+                if let Some(i) = now {
+                    self.counts[i].definite += 1;
+                    self.changed[i] = true;
+                }
+            }
+        }
+        self.dirty = dirty;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{count, CountConstraints, CountTracker};
+    use crate::{
+        grid_2d::{Coordinate2d, Direction2d, Grid2d},
+        rules::{SetCollapseRules, SetCollapseRulesBuilder, UniformSetCollapseObserver},
+        verify, Backtracking, Cause, Collapser, Contradiction, Propagation, Space, State,
+        StateDomain, StateSet,
+    };
+    use rand::{rngs::StdRng, seq::IteratorRandom, Rng, SeedableRng};
+
+    fn assert_counts(tracker: &mut CountTracker<Coordinate2d>, space: &Grid2d<StateSet>) {
+        tracker.update_resolved(space);
+        for (i, &(state, _, _)) in tracker.constraints.limits.iter().enumerate() {
+            assert_eq!(tracker.counts[i], count(space, state), "{state:?}");
+        }
+    }
+
+    #[test]
+    fn tracker_matches_count() {
+        let domain = StateDomain::new(3);
+        let mut space = Grid2d::new(Coordinate2d { x: 4, y: 4 }, |_| domain.all());
+        let mut tracker = CountTracker::new(
+            CountConstraints::new()
+                .with_max(State::nth(0), 5)
+                .with_min(State::nth(2), 1),
+        );
+        tracker.reset(&space);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut removed = Vec::new();
+        for _ in 0..40 {
+            let coordinate = Coordinate2d {
+                x: rng.gen_range(0..4),
+                y: rng.gen_range(0..4),
+            };
+            let Some(state) = space[coordinate].iter().choose(&mut rng) else {
+                continue;
+            };
+            space[coordinate].remove(state);
+            tracker.removed(coordinate, state);
+            removed.push((coordinate, state));
+            assert_counts(&mut tracker, &space);
+        }
+        while let Some((coordinate, state)) = removed.pop() {
+            space[coordinate].add(state);
+            tracker.restored(coordinate, state);
+            assert_counts(&mut tracker, &space);
+        }
+    }
+
+    /// State 0 can't be next to itself, and any other pair is allowed.
+    fn rule() -> SetCollapseRules<UniformSetCollapseObserver> {
+        let domain = StateDomain::new(3);
+        let mut builder =
+            SetCollapseRulesBuilder::<Grid2d<StateSet>, _>::new(domain, UniformSetCollapseObserver);
+        for a in domain.states() {
+            let mut allowed = domain.all();
+            if a == State::nth(0) {
+                allowed.remove(a);
+            }
+            let neighbors = [Direction2d::Right, Direction2d::Up].map(|d| (d, allowed.clone()));
+            builder = builder.allow(a, &neighbors);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn collapse_meets_counts() {
+        let rule = rule();
+        for propagation in [Propagation::Cellwise, Propagation::SupportCount] {
+            for seed in 0..16 {
+                let mut space = Grid2d::new(Coordinate2d { x: 5, y: 4 }, |_| rule.domain().all());
+                Collapser::new(&space)
+                    .with_propagation(propagation)
+                    .with_backtracking(Backtracking::default())
+                    .with_counts(
+                        CountConstraints::new()
+                            .with_exactly(State::nth(0), 4)
+                            .with_max(State::nth(1), 3),
+                    )
+                    .run(&mut space, &rule, &mut StdRng::seed_from_u64(seed), |_| {})
+                    .unwrap();
+                assert!(verify(&space, &rule).is_valid());
+                assert_eq!(count(&space, State::nth(0)).definite, 4);
+                assert!(count(&space, State::nth(1)).definite <= 3);
+            }
+        }
+    }
+
+    #[test]
+    fn count_contradiction_has_no_coordinate() {
+        let rule = rule();
+        for propagation in [Propagation::Cellwise, Propagation::SupportCount] {
+            // Three cells are state 1 to begin with.
+            let mut space = Grid2d::new(Coordinate2d { x: 3, y: 3 }, |c| {
+                if c.y == 1 {
+                    rule.domain().with_states(&[State::nth(1)])
+                } else {
+                    rule.domain().all()
+                }
+            });
+            let result = Collapser::new(&space)
+                .with_propagation(propagation)
+                .with_counts(CountConstraints::new().with_max(State::nth(1), 2))
+                .run(&mut space, &rule, &mut StdRng::seed_from_u64(0), |_| {});
+            assert_eq!(
+                result,
+                Err(Contradiction {
+                    cause: Cause::Count(State::nth(1)),
+                    observed: None,
+                    step: 0,
+                }),
+                "{propagation:?}"
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "count constraint on State(9) outside domain")]
+    fn counts_reject_states_outside_the_domain() {
+        let rule = rule();
+        let space = Grid2d::new(Coordinate2d { x: 3, y: 3 }, |_| rule.domain().all());
+        let _ =
+            Collapser::new(&space).with_counts(CountConstraints::new().with_min(State::nth(9), 1));
+    }
+
+    /// Each pair of 5 states is allowed in each direction with probability
+    /// one half.
+    pub(crate) fn random_rule(rng: &mut StdRng) -> SetCollapseRules<UniformSetCollapseObserver> {
+        let domain = StateDomain::new(5);
+        let mut builder =
+            SetCollapseRulesBuilder::<Grid2d<StateSet>, _>::new(domain, UniformSetCollapseObserver);
+        for a in domain.states() {
+            let neighbors = [Direction2d::Right, Direction2d::Up].map(|d| {
+                let mut allowed = domain.all();
+                for state in domain.states().filter(|_| rng.gen_bool(0.5)) {
+                    allowed.remove(state);
+                }
+                (d, allowed)
+            });
+            builder = builder.allow(a, &neighbors);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn collapse_meets_counts_with_random_rules() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut successes = 0;
+        for _ in 0..64 {
+            let rule = random_rule(&mut rng);
+            let constraints = CountConstraints::new()
+                .with_exactly(State::nth(0), rng.gen_range(0..8))
+                .with_max(State::nth(1), rng.gen_range(0..8))
+                .with_min(State::nth(2), rng.gen_range(0..8));
+            let seed = rng.gen();
+            for propagation in [Propagation::Cellwise, Propagation::SupportCount] {
+                for backtracking in [None, Some(Backtracking::default())] {
+                    let mut space =
+                        Grid2d::new(Coordinate2d { x: 6, y: 5 }, |_| rule.domain().all());
+                    let mut collapser = Collapser::new(&space)
+                        .with_propagation(propagation)
+                        .with_counts(constraints.clone());
+                    if let Some(backtracking) = backtracking {
+                        collapser = collapser.with_backtracking(backtracking);
+                    }
+                    let result =
+                        collapser.run(&mut space, &rule, &mut StdRng::seed_from_u64(seed), |_| {});
+                    if result.is_err() {
+                        continue;
+                    }
+                    successes += 1;
+                    assert!(verify(&space, &rule).is_valid(), "{propagation:?}");
+                    for state in (0..3).map(State::nth) {
+                        let (min, max) = constraints.limits(state);
+                        let count = count(&space, state).definite;
+                        assert!(min <= count && count <= max, "{propagation:?}");
+                    }
+                }
+            }
+        }
+        assert!(successes > 0);
+    }
+}
@@ -0,0 +1,366 @@
+//! Arbitrary graph, for irregular meshes.
+
+use crate::Space;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Debug,
+    marker::PhantomData,
+    ops::{Index, IndexMut, Neg},
+};
+
+/// Graph implementing [`crate::Space`], where cells are nodes connected by
+/// edges between ports `P`.
+///
+/// Each port of a node may be connected to at most one other node, and an
+/// edge that leaves one node by port `p` arrives at the other node by port
+/// `-p`, so that edges may be traversed in either direction.
+///
+/// Graphs have no notion of translation, rotation, or flipping, so they don't
+/// support [`crate::overlapping::codify_patterns`].
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(
+        try_from = "UncheckedGraph<T>",
+        bound(deserialize = "T: Deserialize<'de>, P: Ports")
+    )
+)]
+pub struct Graph<T, P> {
+    cells: Box<[T]>,
+    /// Indexed by `node * P::ALL.len() + port.index()`.
+    edges: Box<[Option<Node>]>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    ports: PhantomData<P>,
+}
+
+/// Deserialized form of [`Graph`], which is checked before use.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(rename = "Graph")]
+struct UncheckedGraph<T> {
+    cells: Box<[T]>,
+    edges: Box<[Option<Node>]>,
+}
+
+/// # Errors
+///
+/// If the number of edges doesn't match the number of nodes and ports, or
+/// any edge is out of range or inconsistent (see [`Graph::with_edge`]).
+#[cfg(feature = "serde")]
+impl<T, P: Ports> TryFrom<UncheckedGraph<T>> for Graph<T, P> {
+    type Error = String;
+
+    fn try_from(graph: UncheckedGraph<T>) -> Result<Self, Self::Error> {
+        let (nodes, ports) = (graph.cells.len(), P::ALL.len());
+        if graph.edges.len() != nodes * ports {
+            return Err(format!(
+                "{} edges for {nodes} nodes with {ports} ports",
+                graph.edges.len()
+            ));
+        }
+        for (i, edge) in graph.edges.iter().enumerate() {
+            let Some(to) = *edge else {
+                continue;
+            };
+            let (from, port) = (Node((i / ports) as u32), P::ALL[i % ports]);
+            if to.0 as usize >= nodes {
+                return Err(format!(
+                    "{port:?} of {from:?} connected to {to:?} out of range"
+                ));
+            }
+            if graph.edges[to.0 as usize * ports + (-port).index()] != Some(from) {
+                return Err(format!("{port:?} of {from:?} connected to {to:?} one way"));
+            }
+        }
+        Ok(Self {
+            cells: graph.cells,
+            edges: graph.edges,
+            ports: PhantomData,
+        })
+    }
+}
+
+/// Node in a [`Graph`], which is also its index in the graph.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct Node(pub u32);
+
+/// Labels of edge slots on a node, which serve as the [`Space::Direction`] of
+/// a [`Graph`].
+pub trait Ports: Copy + Debug + Eq + Neg<Output = Self> + 'static {
+    /// All ports, such that `ALL[port.index()] == port`.
+    const ALL: &'static [Self];
+
+    /// Index of `self` in [`Ports::ALL`].
+    fn index(self) -> usize;
+}
+
+/// Generic set of `K` ports, which are paired such that port `2i` is
+/// opposite port `2i + 1`. `K` must be even.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Port<const K: usize>(usize);
+
+impl<const K: usize> Port<K> {
+    /// The `index`-th port.
+    pub fn new(index: usize) -> Self {
+        assert!(index < K, "port {index} out of range for {K} ports");
+        Self(index)
+    }
+}
+
+impl<const K: usize> Neg for Port<K> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self(self.0 ^ 1)
+    }
+}
+
+impl<const K: usize> Ports for Port<K> {
+    const ALL: &'static [Self] = &{
+        assert!(K.is_multiple_of(2), "ports must be paired");
+        let mut all = [Self(0); K];
+        let mut i = 0;
+        while i < K {
+            all[i] = Self(i);
+            i += 1;
+        }
+        all
+    };
+
+    fn index(self) -> usize {
+        self.0
+    }
+}
+
+impl Index<()> for Node {
+    type Output = u32;
+
+    fn index(&self, _: ()) -> &Self::Output {
+        &self.0
+    }
+}
+
+impl<T, P: Ports> Graph<T, P> {
+    /// Create a graph from an adjacency list, where the `i`-th item lists the
+    /// ports of [`Node`] `i` and the nodes they connect to.
+    ///
+    /// Edges only need to be listed from one side, but may be listed from
+    /// both.
+    ///
+    /// # Panics
+    ///
+    /// If any edges are inconsistent (see [`Graph::with_edge`]).
+    pub fn from_adjacency<E: IntoIterator<Item = (P, Node)>>(
+        adjacency: impl IntoIterator<Item = E>,
+        mut init_fn: impl FnMut(Node) -> T,
+    ) -> Self {
+        let adjacency = adjacency.into_iter().collect::<Vec<_>>();
+        let mut ret = Self::with_nodes(adjacency.len() as u32, &mut init_fn);
+        for (node, edges) in adjacency.into_iter().enumerate() {
+            for (port, neighbor) in edges {
+                ret.connect(Node(node as u32), port, neighbor);
+            }
+        }
+        ret
+    }
+
+    /// Connect `port` of `node` to `-port` of `neighbor`.
+    ///
+    /// # Panics
+    ///
+    /// If either port is already connected to a different node, or either
+    /// node is out of range.
+    pub fn with_edge(mut self, node: Node, port: P, neighbor: Node) -> Self {
+        self.connect(node, port, neighbor);
+        self
+    }
+
+    /// Number of nodes in the graph.
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Whether the graph has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// Create a graph with the same edges, but different cells.
+    pub fn map_cells<U>(&self, mut map_fn: impl FnMut(Node, &T) -> U) -> Graph<U, P> {
+        Graph {
+            cells: self
+                .cells
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| map_fn(Node(i as u32), cell))
+                .collect(),
+            edges: self.edges.clone(),
+            ports: PhantomData,
+        }
+    }
+
+    fn with_nodes(count: u32, init_fn: impl FnMut(Node) -> T) -> Self {
+        Self {
+            cells: (0..count).map(Node).map(init_fn).collect(),
+            edges: vec![None; count as usize * P::ALL.len()].into_boxed_slice(),
+            ports: PhantomData,
+        }
+    }
+
+    fn connect(&mut self, node: Node, port: P, neighbor: Node) {
+        for (from, port, to) in [(node, port, neighbor), (neighbor, -port, node)] {
+            assert!(
+                (from.0 as usize) < self.cells.len(),
+                "{from:?} out of range"
+            );
+            let edge = &mut self.edges[from.0 as usize * P::ALL.len() + port.index()];
+            assert!(
+                edge.is_none_or(|existing| existing == to),
+                "{port:?} of {from:?} already connected to {edge:?}"
+            );
+            *edge = Some(to);
+        }
+    }
+}
+
+impl<T, P> Index<Node> for Graph<T, P> {
+    type Output = T;
+
+    fn index(&self, index: Node) -> &Self::Output {
+        &self.cells[index.0 as usize]
+    }
+}
+
+impl<T, P> IndexMut<Node> for Graph<T, P> {
+    fn index_mut(&mut self, index: Node) -> &mut Self::Output {
+        &mut self.cells[index.0 as usize]
+    }
+}
+
+impl<T: 'static, P: Ports> Space<T> for Graph<T, P> {
+    type Coordinate = Node;
+    type Direction = P;
+    type Axis = ();
+    type RotationAxis = ();
+
+    const DIRECTIONS: &'static [Self::Direction] = P::ALL;
+
+    /// Create a new `Graph` with `dimensions.0` nodes and no edges.
+    fn new(dimensions: Node, init_fn: impl FnMut(Node) -> T) -> Self {
+        Self::with_nodes(dimensions.0, init_fn)
+    }
+
+    fn dimensions(&self) -> Self::Coordinate {
+        Node(self.cells.len() as u32)
+    }
+
+    fn map(
+        coordinate: Self::Coordinate,
+        map_fn: impl Fn(Self::Axis, u32) -> u32,
+    ) -> Self::Coordinate {
+        Node(map_fn((), coordinate.0))
+    }
+
+    fn perp(&self, _: Self::Coordinate, _: Self::RotationAxis) -> Option<Self::Coordinate> {
+        None
+    }
+
+    fn flip(&self, _: Self::Coordinate, _: Self::Axis) -> Option<Self::Coordinate> {
+        None
+    }
+
+    fn add_sub(
+        &self,
+        start: Self::Coordinate,
+        add: Self::Coordinate,
+        sub: Self::Coordinate,
+    ) -> Option<Self::Coordinate> {
+        (add == sub).then_some(start)
+    }
+
+    fn visit_coordinates(&self, visitor: impl FnMut(Self::Coordinate)) {
+        (0..self.cells.len() as u32).map(Node).for_each(visitor);
+    }
+
+    fn neighbor(
+        &self,
+        coord: Self::Coordinate,
+        direction: Self::Direction,
+    ) -> Option<Self::Coordinate> {
+        self.edges[coord.0 as usize * P::ALL.len() + direction.index()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Graph, Node, Port};
+    use crate::{collapse, sockets::tests::pipes, verify::tests::assert_valid, StateSet};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[cfg(feature = "serde")]
+    type Ports = Port<2>;
+
+    #[cfg(feature = "serde")]
+    fn path() -> Graph<u32, Ports> {
+        Graph::from_adjacency(
+            [
+                vec![(Port::new(0), Node(1))],
+                vec![(Port::new(0), Node(2))],
+                vec![],
+            ],
+            |node| node.0,
+        )
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let graph = path();
+        let json = serde_json::to_value(&graph).unwrap();
+        assert_eq!(
+            serde_json::from_value::<Graph<u32, Ports>>(json).unwrap(),
+            graph
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_rejects_corrupt_edges() {
+        let json = serde_json::to_value(path()).unwrap();
+        let error = |edit: fn(&mut Vec<serde_json::Value>)| {
+            let mut corrupt = json.clone();
+            edit(corrupt["edges"].as_array_mut().unwrap());
+            serde_json::from_value::<Graph<u32, Ports>>(corrupt)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error(|edges| drop(edges.pop())).contains("5 edges for 3 nodes with 2 ports"));
+        assert!(error(|edges| edges[4] = 3.into()).contains("out of range"));
+        assert!(error(|edges| edges[0] = 2.into()).contains("one way"));
+    }
+
+    #[test]
+    fn collapse_graph() {
+        // A 4x4 torus, where ports 0 and 1 lead along x, and 2 and 3 along y.
+        let node = |x: u32, y: u32| Node(x % 4 + y % 4 * 4);
+        let torus = Graph::<_, Port<4>>::from_adjacency(
+            (0..16).map(|i| {
+                let (x, y) = (i % 4, i / 4);
+                [
+                    (Port::new(0), node(x + 1, y)),
+                    (Port::new(2), node(x, y + 1)),
+                ]
+            }),
+            |_| (),
+        );
+        let rule = pipes::<Graph<StateSet, Port<4>>, _>();
+        for seed in 0..4 {
+            let mut space = torus.map_cells(|_, _| rule.domain().all());
+            collapse(&mut space, &rule, &mut StdRng::seed_from_u64(seed), |_| {}).unwrap();
+            assert_valid(&space, &rule);
+        }
+    }
+}
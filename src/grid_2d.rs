@@ -1,19 +1,61 @@
 //! 2D grid.
 
-use crate::Space;
+use crate::{space::offset, Space};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::ops::{Index, IndexMut, Neg};
 
 /// Basic 2D grid implementing [`crate::Space`].
 ///
-/// Coordinates are specified as [`Coordinate2d`].
+/// Coordinates are specified as [`Coordinate2d`]. By default, cells on the
+/// edges of the grid have no neighbors beyond the edge, but each axis can be
+/// made periodic with [`Grid2d::with_periodic`].
 #[derive(Hash, PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(try_from = "UncheckedGrid2d<T>")
+)]
 pub struct Grid2d<T> {
     cells: Box<[T]>,
     dimensions: Coordinate2d,
+    /// Indexed by [`Axis2d`].
+    periodic: [bool; 2],
+}
+
+/// Deserialized form of [`Grid2d`], which is checked before use.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(rename = "Grid2d")]
+struct UncheckedGrid2d<T> {
+    cells: Box<[T]>,
+    dimensions: Coordinate2d,
+    periodic: [bool; 2],
+}
+
+/// # Errors
+///
+/// If the number of cells doesn't match the dimensions.
+#[cfg(feature = "serde")]
+impl<T> TryFrom<UncheckedGrid2d<T>> for Grid2d<T> {
+    type Error = String;
+
+    fn try_from(grid: UncheckedGrid2d<T>) -> Result<Self, Self::Error> {
+        let Coordinate2d { x, y } = grid.dimensions;
+        if grid.cells.len() as u64 != x as u64 * y as u64 {
+            return Err(format!("{} cells for {x}x{y} grid", grid.cells.len()));
+        }
+        Ok(Self {
+            cells: grid.cells,
+            dimensions: grid.dimensions,
+            periodic: grid.periodic,
+        })
+    }
 }
 
 /// 2D coordinate.
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[allow(missing_docs)]
 pub struct Coordinate2d {
     pub x: u32,
@@ -22,6 +64,7 @@ pub struct Coordinate2d {
 
 /// Direction to adjacent neighbor in 2D space.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Direction2d {
     /// +x
     Right,
@@ -35,6 +78,7 @@ pub enum Direction2d {
 
 /// 2D coordinate axis.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[allow(missing_docs)]
 pub enum Axis2d {
     X,
@@ -76,6 +120,20 @@ impl Neg for Direction2d {
     }
 }
 
+impl<T> Grid2d<T> {
+    /// Set whether coordinates wrap around along `axis`, such that the first
+    /// and last cells along it are neighbors.
+    pub fn with_periodic(mut self, axis: Axis2d, periodic: bool) -> Self {
+        self.periodic[axis as usize] = periodic;
+        self
+    }
+
+    /// Whether coordinates wrap around along `axis`.
+    pub fn is_periodic(&self, axis: Axis2d) -> bool {
+        self.periodic[axis as usize]
+    }
+}
+
 impl<T> Index<Coordinate2d> for Grid2d<T> {
     type Output = T;
 
@@ -116,6 +174,7 @@ impl<T: 'static> Space<T> for Grid2d<T> {
         Self {
             cells: cells.into_boxed_slice(),
             dimensions,
+            periodic: [false; 2],
         }
     }
 
@@ -133,12 +192,16 @@ impl<T: 'static> Space<T> for Grid2d<T> {
         }
     }
 
-    fn perp(&self, coordinate: Self::Coordinate, _: Self::RotationAxis) -> Self::Coordinate {
+    fn perp(
+        &self,
+        coordinate: Self::Coordinate,
+        _: Self::RotationAxis,
+    ) -> Option<Self::Coordinate> {
         assert_eq!(self.dimensions.x, self.dimensions.y);
-        Coordinate2d {
+        Some(Coordinate2d {
             x: self.dimensions.y - 1 - coordinate.y,
             y: coordinate.x,
-        }
+        })
     }
 
     fn add_sub(
@@ -147,15 +210,14 @@ impl<T: 'static> Space<T> for Grid2d<T> {
         add: Self::Coordinate,
         sub: Self::Coordinate,
     ) -> Option<Self::Coordinate> {
-        let x = start.x.checked_add_signed(add.x as i32 - sub.x as i32)?;
-        let y = start.y.checked_add_signed(add.y as i32 - sub.y as i32)?;
-        if x >= self.dimensions.x || y >= self.dimensions.y {
-            return None;
-        }
+        let [px, py] = self.periodic;
+        let x = offset(start.x, add.x as i64 - sub.x as i64, self.dimensions.x, px)?;
+        let y = offset(start.y, add.y as i64 - sub.y as i64, self.dimensions.y, py)?;
         Some(Coordinate2d { x, y })
     }
 
-    fn visit_coordinates(dimensions: Self::Coordinate, mut visitor: impl FnMut(Self::Coordinate)) {
+    fn visit_coordinates(&self, mut visitor: impl FnMut(Self::Coordinate)) {
+        let dimensions = self.dimensions;
         for y in 0..dimensions.y {
             for x in 0..dimensions.x {
                 visitor(Coordinate2d { x, y });
@@ -168,18 +230,63 @@ impl<T: 'static> Space<T> for Grid2d<T> {
         coord: Self::Coordinate,
         direction: Self::Direction,
     ) -> Option<Self::Coordinate> {
-        let Coordinate2d { x, y } = coord;
+        let [px, py] = self.periodic;
         let (dx, dy) = direction.offset();
-        if (x == 0 && dx == -1) || (y == 0 && dy == -1) {
-            None
-        } else if (x == self.dimensions.x - 1 && dx == 1) || (y == self.dimensions.y - 1 && dy == 1)
-        {
+        Some(Coordinate2d {
+            x: offset(coord.x, dx as i64, self.dimensions.x, px)?,
+            y: offset(coord.y, dy as i64, self.dimensions.y, py)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Axis2d, Coordinate2d, Direction2d, Grid2d};
+    use crate::{collapse::tests::constrains, Space};
+
+    #[test]
+    fn empty_periodic_axis() {
+        let grid = Grid2d::new(Coordinate2d { x: 0, y: 3 }, |_| ()).with_periodic(Axis2d::X, true);
+        let start = Coordinate2d { x: 0, y: 1 };
+        assert_eq!(grid.neighbor(start, Direction2d::Right), None);
+        assert_eq!(
+            grid.add_sub(start, Coordinate2d { x: 1, y: 0 }, start),
             None
-        } else {
-            Some(Coordinate2d {
-                x: x.wrapping_add_signed(dx),
-                y: y.wrapping_add_signed(dy),
-            })
+        );
+    }
+
+    #[test]
+    fn periodic_wraps_constraints() {
+        for periodic in [false, true] {
+            let grid = |g: Grid2d<_>| {
+                g.with_periodic(Axis2d::X, periodic)
+                    .with_periodic(Axis2d::Y, periodic)
+            };
+            let dimensions = Coordinate2d { x: 4, y: 5 };
+            let first = Coordinate2d { x: 0, y: 0 };
+            let right = Coordinate2d { x: 3, y: 0 };
+            let up = Coordinate2d { x: 0, y: 4 };
+            let constrains_right = constrains(dimensions, grid, Direction2d::Right, first, right);
+            assert_eq!(constrains_right, periodic);
+            let constrains_up = constrains(dimensions, grid, Direction2d::Up, first, up);
+            assert_eq!(constrains_up, periodic);
         }
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let grid = Grid2d::new(Coordinate2d { x: 3, y: 2 }, |c| c.x + 10 * c.y)
+            .with_periodic(Axis2d::X, true);
+        let json = serde_json::to_value(&grid).unwrap();
+        assert_eq!(
+            serde_json::from_value::<Grid2d<u32>>(json.clone()).unwrap(),
+            grid
+        );
+
+        let mut corrupt = json;
+        corrupt["cells"].as_array_mut().unwrap().pop();
+        let error = serde_json::from_value::<Grid2d<u32>>(corrupt).unwrap_err();
+        assert!(error.to_string().contains("5 cells for 3x2 grid"));
+    }
 }
@@ -1,19 +1,61 @@
 //! 3D grid.
 
-use crate::Space;
+use crate::{space::offset, Space};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::ops::{Index, IndexMut, Neg};
 
 /// Basic 3D grid implementing [`crate::Space`].
 ///
-/// Coordinates are specified as [`Coordinate3d`].
+/// Coordinates are specified as [`Coordinate3d`]. By default, cells on the
+/// edges of the grid have no neighbors beyond the edge, but each axis can be
+/// made periodic with [`Grid3d::with_periodic`].
 #[derive(Hash, PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(try_from = "UncheckedGrid3d<T>")
+)]
 pub struct Grid3d<T> {
     cells: Box<[T]>,
     dimensions: Coordinate3d,
+    /// Indexed by [`Axis3d`].
+    periodic: [bool; 3],
+}
+
+/// Deserialized form of [`Grid3d`], which is checked before use.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(rename = "Grid3d")]
+struct UncheckedGrid3d<T> {
+    cells: Box<[T]>,
+    dimensions: Coordinate3d,
+    periodic: [bool; 3],
+}
+
+/// # Errors
+///
+/// If the number of cells doesn't match the dimensions.
+#[cfg(feature = "serde")]
+impl<T> TryFrom<UncheckedGrid3d<T>> for Grid3d<T> {
+    type Error = String;
+
+    fn try_from(grid: UncheckedGrid3d<T>) -> Result<Self, Self::Error> {
+        let Coordinate3d { x, y, z } = grid.dimensions;
+        if grid.cells.len() as u128 != x as u128 * y as u128 * z as u128 {
+            return Err(format!("{} cells for {x}x{y}x{z} grid", grid.cells.len()));
+        }
+        Ok(Self {
+            cells: grid.cells,
+            dimensions: grid.dimensions,
+            periodic: grid.periodic,
+        })
+    }
 }
 
 /// 3D coordinate.
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[allow(missing_docs)]
 pub struct Coordinate3d {
     pub x: u32,
@@ -23,6 +65,7 @@ pub struct Coordinate3d {
 
 /// Direction to adjacent neighbor in 3D space.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[allow(missing_docs)]
 pub enum Direction3d {
     PosX,
@@ -35,6 +78,7 @@ pub enum Direction3d {
 
 /// 3D coordinate axis.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[allow(missing_docs)]
 pub enum Axis3d {
     X,
@@ -82,6 +126,20 @@ impl Neg for Direction3d {
     }
 }
 
+impl<T> Grid3d<T> {
+    /// Set whether coordinates wrap around along `axis`, such that the first
+    /// and last cells along it are neighbors.
+    pub fn with_periodic(mut self, axis: Axis3d, periodic: bool) -> Self {
+        self.periodic[axis as usize] = periodic;
+        self
+    }
+
+    /// Whether coordinates wrap around along `axis`.
+    pub fn is_periodic(&self, axis: Axis3d) -> bool {
+        self.periodic[axis as usize]
+    }
+}
+
 impl<T> Index<Coordinate3d> for Grid3d<T> {
     type Output = T;
 
@@ -128,6 +186,7 @@ impl<T: 'static> Space<T> for Grid3d<T> {
         Self {
             cells: cells.into_boxed_slice(),
             dimensions,
+            periodic: [false; 3],
         }
     }
 
@@ -146,7 +205,11 @@ impl<T: 'static> Space<T> for Grid3d<T> {
         }
     }
 
-    fn perp(&self, mut coordinate: Self::Coordinate, axis: Self::RotationAxis) -> Self::Coordinate {
+    fn perp(
+        &self,
+        mut coordinate: Self::Coordinate,
+        axis: Self::RotationAxis,
+    ) -> Option<Self::Coordinate> {
         let (c1, c2, d1, d2) = match axis {
             Axis3d::X => (
                 &mut coordinate.y,
@@ -174,7 +237,7 @@ impl<T: 'static> Space<T> for Grid3d<T> {
         *c2 = *c1;
         *c1 = d2 - 1 - c2_copy;
 
-        coordinate
+        Some(coordinate)
     }
 
     fn add_sub(
@@ -183,16 +246,15 @@ impl<T: 'static> Space<T> for Grid3d<T> {
         add: Self::Coordinate,
         sub: Self::Coordinate,
     ) -> Option<Self::Coordinate> {
-        let x = start.x.checked_add_signed(add.x as i32 - sub.x as i32)?;
-        let y = start.y.checked_add_signed(add.y as i32 - sub.y as i32)?;
-        let z = start.z.checked_add_signed(add.z as i32 - sub.z as i32)?;
-        if x >= self.dimensions.x || y >= self.dimensions.y || z >= self.dimensions.z {
-            return None;
-        }
+        let [px, py, pz] = self.periodic;
+        let x = offset(start.x, add.x as i64 - sub.x as i64, self.dimensions.x, px)?;
+        let y = offset(start.y, add.y as i64 - sub.y as i64, self.dimensions.y, py)?;
+        let z = offset(start.z, add.z as i64 - sub.z as i64, self.dimensions.z, pz)?;
         Some(Coordinate3d { x, y, z })
     }
 
-    fn visit_coordinates(dimensions: Self::Coordinate, mut visitor: impl FnMut(Self::Coordinate)) {
+    fn visit_coordinates(&self, mut visitor: impl FnMut(Self::Coordinate)) {
+        let dimensions = self.dimensions;
         for z in 0..dimensions.z {
             for y in 0..dimensions.y {
                 for x in 0..dimensions.x {
@@ -207,21 +269,53 @@ impl<T: 'static> Space<T> for Grid3d<T> {
         coord: Self::Coordinate,
         direction: Self::Direction,
     ) -> Option<Self::Coordinate> {
-        let Coordinate3d { x, y, z } = coord;
+        let [px, py, pz] = self.periodic;
         let (dx, dy, dz) = direction.offset();
-        if (x == 0 && dx == -1) || (y == 0 && dy == -1) || (z == 0 && dz == -1) {
-            None
-        } else if (x == self.dimensions.x - 1 && dx == 1)
-            || (y == self.dimensions.y - 1 && dy == 1)
-            || (z == self.dimensions.z - 1 && dz == 1)
-        {
-            None
-        } else {
-            Some(Coordinate3d {
-                x: x.wrapping_add_signed(dx),
-                y: y.wrapping_add_signed(dy),
-                z: z.wrapping_add_signed(dz),
-            })
+        Some(Coordinate3d {
+            x: offset(coord.x, dx as i64, self.dimensions.x, px)?,
+            y: offset(coord.y, dy as i64, self.dimensions.y, py)?,
+            z: offset(coord.z, dz as i64, self.dimensions.z, pz)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Axis3d, Coordinate3d, Direction3d, Grid3d};
+    use crate::collapse::tests::constrains;
+    #[cfg(feature = "serde")]
+    use crate::Space;
+
+    #[test]
+    fn periodic_wraps_constraints() {
+        for periodic in [false, true] {
+            let constrained = constrains(
+                Coordinate3d { x: 2, y: 2, z: 4 },
+                |g: Grid3d<_>| g.with_periodic(Axis3d::Z, periodic),
+                Direction3d::PosZ,
+                Coordinate3d { x: 1, y: 0, z: 0 },
+                Coordinate3d { x: 1, y: 0, z: 3 },
+            );
+            assert_eq!(constrained, periodic);
         }
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let grid = Grid3d::new(Coordinate3d { x: 2, y: 2, z: 3 }, |c| {
+            c.x + 2 * c.y + 4 * c.z
+        })
+        .with_periodic(Axis3d::Z, true);
+        let json = serde_json::to_value(&grid).unwrap();
+        assert_eq!(
+            serde_json::from_value::<Grid3d<u32>>(json.clone()).unwrap(),
+            grid
+        );
+
+        let mut corrupt = json;
+        corrupt["dimensions"]["z"] = 4.into();
+        let error = serde_json::from_value::<Grid3d<u32>>(corrupt).unwrap_err();
+        assert!(error.to_string().contains("12 cells for 2x2x4 grid"));
+    }
 }
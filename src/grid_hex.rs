@@ -0,0 +1,490 @@
+//! Hexagonal grid.
+
+use crate::Space;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Debug,
+    hash::Hash,
+    marker::PhantomData,
+    ops::{Add, Index, IndexMut, Neg, Sub},
+};
+
+/// Hexagonal grid implementing [`crate::Space`], with hexagons oriented
+/// according to `L` (either [`PointyTop`] or [`FlatTop`]).
+///
+/// Coordinates are specified as [`CoordinateHex`], which are offset
+/// coordinates such that the grid is roughly rectangular. Neighbors,
+/// translations, rotations and reflections are computed in [`AxialHex`]
+/// coordinates.
+///
+/// Rotations and reflections are about the center cell of the grid, and only
+/// defined for cells within the largest hexagon around it that fits in the
+/// grid. For example, a 3x3 pattern consists of a center cell and its six
+/// neighbors, and the two remaining corner cells are ignored if symmetries
+/// are used.
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(try_from = "UncheckedGridHex<T>")
+)]
+pub struct GridHex<T, L = PointyTop> {
+    cells: Box<[T]>,
+    dimensions: CoordinateHex,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    layout: PhantomData<L>,
+}
+
+/// Deserialized form of [`GridHex`], which is checked before use.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(rename = "GridHex")]
+struct UncheckedGridHex<T> {
+    cells: Box<[T]>,
+    dimensions: CoordinateHex,
+}
+
+/// # Errors
+///
+/// If the number of cells doesn't match the dimensions.
+#[cfg(feature = "serde")]
+impl<T, L> TryFrom<UncheckedGridHex<T>> for GridHex<T, L> {
+    type Error = String;
+
+    fn try_from(grid: UncheckedGridHex<T>) -> Result<Self, Self::Error> {
+        let CoordinateHex { x, y } = grid.dimensions;
+        if grid.cells.len() as u64 != x as u64 * y as u64 {
+            return Err(format!("{} cells for {x}x{y} grid", grid.cells.len()));
+        }
+        Ok(Self {
+            cells: grid.cells,
+            dimensions: grid.dimensions,
+            layout: PhantomData,
+        })
+    }
+}
+
+/// Offset coordinate of a hexagon, as column `x` and row `y`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[allow(missing_docs)]
+pub struct CoordinateHex {
+    pub x: u32,
+    pub y: u32,
+}
+
+/// Axial coordinate of a hexagon, which is convenient for hex math. The
+/// implicit third cube coordinate is `-q - r`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[allow(missing_docs)]
+pub struct AxialHex {
+    pub q: i32,
+    pub r: i32,
+}
+
+/// Direction to adjacent neighbor in hexagonal space, in counter-clockwise
+/// order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DirectionHex {
+    /// +q
+    PosQ,
+    /// +r
+    PosR,
+    /// -q +r
+    NegQPosR,
+    /// -q
+    NegQ,
+    /// -r
+    NegR,
+    /// +q -r
+    PosQNegR,
+}
+
+/// Hexagonal coordinate axis.
+///
+/// As an axis of reflection, [`AxisHex::X`] mirrors horizontally and
+/// [`AxisHex::Y`] mirrors vertically.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[allow(missing_docs)]
+pub enum AxisHex {
+    X,
+    Y,
+}
+
+/// Orientation of the hexagons in a [`GridHex`], which determines how offset
+/// coordinates relate to axial coordinates.
+pub trait Layout: Copy + Debug + Default + Hash + Eq + 'static {
+    /// Convert an offset coordinate to an axial coordinate.
+    fn to_axial(coordinate: CoordinateHex) -> AxialHex;
+
+    /// Convert an axial coordinate to an offset coordinate, returning `None`
+    /// if it would be negative.
+    fn to_offset(axial: AxialHex) -> Option<CoordinateHex>;
+
+    /// Reflect `axial` across `axis` through the origin.
+    fn flip(axial: AxialHex, axis: AxisHex) -> AxialHex;
+}
+
+/// Hexagons with a vertex at the top, arranged in rows (`y`). Odd rows are
+/// shifted half a hexagon in the +x direction, and +q points in the +x
+/// direction.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PointyTop;
+
+/// Hexagons with an edge at the top, arranged in columns (`x`). Odd columns
+/// are shifted half a hexagon in the +y direction, and +r points in the +y
+/// direction.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FlatTop;
+
+impl Layout for PointyTop {
+    fn to_axial(coordinate: CoordinateHex) -> AxialHex {
+        let CoordinateHex { x, y } = coordinate;
+        AxialHex {
+            q: x as i32 - (y as i32).div_euclid(2),
+            r: y as i32,
+        }
+    }
+
+    fn to_offset(axial: AxialHex) -> Option<CoordinateHex> {
+        let AxialHex { q, r } = axial;
+        Some(CoordinateHex {
+            x: (q + r.div_euclid(2)).try_into().ok()?,
+            y: r.try_into().ok()?,
+        })
+    }
+
+    fn flip(axial: AxialHex, axis: AxisHex) -> AxialHex {
+        let AxialHex { q, r } = axial;
+        match axis {
+            AxisHex::X => AxialHex { q: -q - r, r },
+            AxisHex::Y => AxialHex { q: q + r, r: -r },
+        }
+    }
+}
+
+impl Layout for FlatTop {
+    fn to_axial(coordinate: CoordinateHex) -> AxialHex {
+        let CoordinateHex { x, y } = coordinate;
+        AxialHex {
+            q: x as i32,
+            r: y as i32 - (x as i32).div_euclid(2),
+        }
+    }
+
+    fn to_offset(axial: AxialHex) -> Option<CoordinateHex> {
+        let AxialHex { q, r } = axial;
+        Some(CoordinateHex {
+            x: q.try_into().ok()?,
+            y: (r + q.div_euclid(2)).try_into().ok()?,
+        })
+    }
+
+    fn flip(axial: AxialHex, axis: AxisHex) -> AxialHex {
+        let AxialHex { q, r } = axial;
+        match axis {
+            AxisHex::X => AxialHex { q: -q, r: q + r },
+            AxisHex::Y => AxialHex { q, r: -q - r },
+        }
+    }
+}
+
+impl AxialHex {
+    /// Number of steps between `self` and the origin.
+    pub fn length(self) -> u32 {
+        (self.q.unsigned_abs() + self.r.unsigned_abs() + (self.q + self.r).unsigned_abs()) / 2
+    }
+
+    /// 60 degree counter-clockwise rotation around the origin.
+    pub fn perp(self) -> Self {
+        Self {
+            q: -self.r,
+            r: self.q + self.r,
+        }
+    }
+}
+
+impl Add for AxialHex {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            q: self.q + rhs.q,
+            r: self.r + rhs.r,
+        }
+    }
+}
+
+impl Sub for AxialHex {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            q: self.q - rhs.q,
+            r: self.r - rhs.r,
+        }
+    }
+}
+
+impl Index<AxisHex> for CoordinateHex {
+    type Output = u32;
+
+    fn index(&self, index: AxisHex) -> &Self::Output {
+        match index {
+            AxisHex::X => &self.x,
+            AxisHex::Y => &self.y,
+        }
+    }
+}
+
+impl DirectionHex {
+    /// Axial offset to the neighbor in this direction.
+    pub fn offset(self) -> AxialHex {
+        let (q, r) = match self {
+            Self::PosQ => (1, 0),
+            Self::PosR => (0, 1),
+            Self::NegQPosR => (-1, 1),
+            Self::NegQ => (-1, 0),
+            Self::NegR => (0, -1),
+            Self::PosQNegR => (1, -1),
+        };
+        AxialHex { q, r }
+    }
+}
+
+impl Neg for DirectionHex {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        match self {
+            Self::PosQ => Self::NegQ,
+            Self::PosR => Self::NegR,
+            Self::NegQPosR => Self::PosQNegR,
+            Self::NegQ => Self::PosQ,
+            Self::NegR => Self::PosR,
+            Self::PosQNegR => Self::NegQPosR,
+        }
+    }
+}
+
+impl<T, L: Layout> GridHex<T, L> {
+    /// Convert `coordinate` to an axial coordinate.
+    pub fn to_axial(&self, coordinate: CoordinateHex) -> AxialHex {
+        L::to_axial(coordinate)
+    }
+
+    /// Convert `axial` to an offset coordinate, returning `Some` if the
+    /// result is in the grid.
+    pub fn to_offset(&self, axial: AxialHex) -> Option<CoordinateHex> {
+        L::to_offset(axial).filter(|c| c.x < self.dimensions.x && c.y < self.dimensions.y)
+    }
+
+    /// Apply `transform_fn` to `coordinate` relative to the center of the grid,
+    /// as long as it is within the largest hexagon around the center.
+    fn transform(
+        &self,
+        coordinate: CoordinateHex,
+        transform_fn: impl FnOnce(AxialHex) -> AxialHex,
+    ) -> Option<CoordinateHex> {
+        let radius = (self.dimensions.x.min(self.dimensions.y).saturating_sub(1)) / 2;
+        let center = L::to_axial(CoordinateHex {
+            x: self.dimensions.x / 2,
+            y: self.dimensions.y / 2,
+        });
+        let relative = L::to_axial(coordinate) - center;
+        if relative.length() > radius {
+            return None;
+        }
+        self.to_offset(transform_fn(relative) + center)
+    }
+}
+
+impl<T, L> Index<CoordinateHex> for GridHex<T, L> {
+    type Output = T;
+
+    fn index(&self, index: CoordinateHex) -> &Self::Output {
+        let CoordinateHex { x, y } = index;
+        &self.cells[(x + y * self.dimensions.x) as usize]
+    }
+}
+
+impl<T, L> IndexMut<CoordinateHex> for GridHex<T, L> {
+    fn index_mut(&mut self, index: CoordinateHex) -> &mut Self::Output {
+        let CoordinateHex { x, y } = index;
+        &mut self.cells[(x + y * self.dimensions.x) as usize]
+    }
+}
+
+impl<T: 'static, L: Layout> Space<T> for GridHex<T, L> {
+    type Coordinate = CoordinateHex;
+    type Direction = DirectionHex;
+    type Axis = AxisHex;
+    type RotationAxis = ();
+
+    const DIRECTIONS: &'static [Self::Direction] = &[
+        DirectionHex::PosQ,
+        DirectionHex::PosR,
+        DirectionHex::NegQPosR,
+        DirectionHex::NegQ,
+        DirectionHex::NegR,
+        DirectionHex::PosQNegR,
+    ];
+
+    const ROTATIONS: u32 = 6;
+
+    /// Create a new `GridHex`
+    fn new(dimensions: CoordinateHex, mut init_fn: impl FnMut(CoordinateHex) -> T) -> Self {
+        let mut cells = Vec::with_capacity((dimensions.x * dimensions.y) as usize);
+        for y in 0..dimensions.y {
+            for x in 0..dimensions.x {
+                cells.push(init_fn(CoordinateHex { x, y }));
+            }
+        }
+        Self {
+            cells: cells.into_boxed_slice(),
+            dimensions,
+            layout: PhantomData,
+        }
+    }
+
+    fn dimensions(&self) -> Self::Coordinate {
+        self.dimensions
+    }
+
+    fn map(
+        coordinate: Self::Coordinate,
+        map_fn: impl Fn(Self::Axis, u32) -> u32,
+    ) -> Self::Coordinate {
+        CoordinateHex {
+            x: map_fn(AxisHex::X, coordinate.x),
+            y: map_fn(AxisHex::Y, coordinate.y),
+        }
+    }
+
+    fn perp(
+        &self,
+        coordinate: Self::Coordinate,
+        _: Self::RotationAxis,
+    ) -> Option<Self::Coordinate> {
+        self.transform(coordinate, AxialHex::perp)
+    }
+
+    fn flip(&self, coordinate: Self::Coordinate, axis: Self::Axis) -> Option<Self::Coordinate> {
+        self.transform(coordinate, |axial| L::flip(axial, axis))
+    }
+
+    fn add_sub(
+        &self,
+        start: Self::Coordinate,
+        add: Self::Coordinate,
+        sub: Self::Coordinate,
+    ) -> Option<Self::Coordinate> {
+        self.to_offset(L::to_axial(start) + L::to_axial(add) - L::to_axial(sub))
+    }
+
+    fn visit_coordinates(&self, mut visitor: impl FnMut(Self::Coordinate)) {
+        let dimensions = self.dimensions;
+        for y in 0..dimensions.y {
+            for x in 0..dimensions.x {
+                visitor(CoordinateHex { x, y });
+            }
+        }
+    }
+
+    fn neighbor(
+        &self,
+        coord: Self::Coordinate,
+        direction: Self::Direction,
+    ) -> Option<Self::Coordinate> {
+        self.to_offset(L::to_axial(coord) + direction.offset())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CoordinateHex, FlatTop, GridHex, Layout, PointyTop};
+    use crate::Space;
+
+    /// Check the neighbors of `even` and `odd`, which are in an even and an
+    /// odd row or column, against `expected`, in the order of
+    /// [`Space::DIRECTIONS`], and that every neighbor, translation and
+    /// rotation in a 5x5 grid is consistent with them.
+    fn check_neighbors<L: Layout>(
+        even: CoordinateHex,
+        odd: CoordinateHex,
+        expected: [[(u32, u32); 6]; 2],
+    ) {
+        let grid = GridHex::<(), L>::new(CoordinateHex { x: 5, y: 5 }, |_| ());
+        let directions = GridHex::<(), L>::DIRECTIONS;
+        for (start, expected) in [even, odd].into_iter().zip(expected) {
+            for (&direction, (x, y)) in directions.iter().zip(expected) {
+                assert_eq!(
+                    grid.neighbor(start, direction),
+                    Some(CoordinateHex { x, y }),
+                    "{start:?} {direction:?}"
+                );
+            }
+        }
+
+        let center = CoordinateHex { x: 2, y: 2 };
+        grid.visit_coordinates(|start| {
+            for (i, &direction) in directions.iter().enumerate() {
+                let Some(neighbor) = grid.neighbor(start, direction) else {
+                    continue;
+                };
+                assert_eq!(grid.neighbor(neighbor, -direction), Some(start));
+                let offset = grid.neighbor(center, direction).unwrap();
+                assert_eq!(grid.add_sub(start, offset, center), Some(neighbor));
+                assert_eq!(grid.add_sub(neighbor, center, offset), Some(start));
+
+                // Rotating by 60 degrees turns each direction into the next.
+                let next = directions[(i + 1) % directions.len()];
+                assert_eq!(grid.perp(offset, ()), grid.neighbor(center, next));
+            }
+        });
+    }
+
+    #[test]
+    fn neighbors_pointy_top() {
+        check_neighbors::<PointyTop>(
+            CoordinateHex { x: 2, y: 2 },
+            CoordinateHex { x: 2, y: 3 },
+            [
+                [(3, 2), (2, 3), (1, 3), (1, 2), (1, 1), (2, 1)],
+                [(3, 3), (3, 4), (2, 4), (1, 3), (2, 2), (3, 2)],
+            ],
+        );
+    }
+
+    #[test]
+    fn neighbors_flat_top() {
+        check_neighbors::<FlatTop>(
+            CoordinateHex { x: 2, y: 2 },
+            CoordinateHex { x: 3, y: 2 },
+            [
+                [(3, 2), (2, 3), (1, 2), (1, 1), (2, 1), (3, 1)],
+                [(4, 3), (3, 3), (2, 3), (2, 2), (3, 1), (4, 2)],
+            ],
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let grid = GridHex::<_, FlatTop>::new(CoordinateHex { x: 3, y: 3 }, |c| c.x * c.y);
+        let json = serde_json::to_value(&grid).unwrap();
+        assert_eq!(
+            serde_json::from_value::<GridHex<u32, FlatTop>>(json.clone()).unwrap(),
+            grid
+        );
+
+        let mut corrupt = json;
+        corrupt["cells"].as_array_mut().unwrap().push(0.into());
+        let error = serde_json::from_value::<GridHex<u32, FlatTop>>(corrupt).unwrap_err();
+        assert!(error.to_string().contains("10 cells for 3x3 grid"));
+    }
+}
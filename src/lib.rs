@@ -12,14 +12,27 @@
 
 #[cfg(all(test, not(miri)))]
 mod benches;
+pub mod chunks;
 mod collapse;
+pub mod counts;
+pub mod graph;
 pub mod grid_2d;
 pub mod grid_3d;
+pub mod grid_hex;
+pub mod order;
 pub mod overlapping;
 pub mod rules;
+pub mod sockets;
 mod space;
 mod state;
+mod support;
+pub mod synthesis;
+pub mod tiled;
+mod verify;
+#[cfg(feature = "xml")]
+pub mod xml;
 
 pub use collapse::*;
 pub use space::*;
 pub use state::*;
+pub use verify::*;
@@ -0,0 +1,441 @@
+//! Collapse order.
+
+use crate::{
+    grid_2d::{Coordinate2d, Grid2d},
+    grid_3d::{Coordinate3d, Grid3d},
+    grid_hex::{CoordinateHex, GridHex, Layout},
+    rules::{SetCollapseObserver, SetCollapseRules},
+    state::{State, StateSet, Superposition},
+    Space,
+};
+use rand::Rng;
+use std::collections::HashMap;
+
+/// Chooses which cell to observe next.
+pub trait CollapseOrder<Sp: Space<S>, S: Superposition = StateSet> {
+    /// Choose the next cell to observe, or `None` if every cell is resolved.
+    ///
+    /// `unresolved` contains every unresolved cell, and possibly some resolved
+    /// cells, which may be removed.
+    fn select<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        unresolved: &mut Vec<Sp::Coordinate>,
+        space: &Sp,
+        rule: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+    ) -> Option<Sp::Coordinate>;
+
+    /// Called when `state` is removed from the cell at `coordinate`.
+    fn removed<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        _coordinate: Sp::Coordinate,
+        _state: State,
+        _rule: &SetCollapseRules<O, S>,
+    ) {
+    }
+
+    /// Called when `state` is restored to the cell at `coordinate` by
+    /// backtracking.
+    fn restored<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        _coordinate: Sp::Coordinate,
+        _state: State,
+        _rule: &SetCollapseRules<O, S>,
+    ) {
+    }
+}
+
+/// Remove resolved cells from `unresolved` (those where `key` is `None`) and
+/// select one of the cells with the lowest `key`, uniformly at random.
+///
+/// `ties` is reused between calls, holding indices into `unresolved`.
+fn select_lowest<C: Copy, K: PartialOrd + Copy>(
+    unresolved: &mut Vec<C>,
+    ties: &mut Vec<usize>,
+    rng: &mut impl Rng,
+    mut key: impl FnMut(C) -> Option<K>,
+) -> Option<C> {
+    let mut lowest = None;
+    let mut kept = 0;
+    ties.clear();
+    unresolved.retain(|&unresolved| {
+        let Some(k) = key(unresolved) else {
+            return false;
+        };
+        match lowest {
+            Some(l) if k > l => {}
+            Some(l) if k == l => ties.push(kept),
+            _ => {
+                lowest = Some(k);
+                ties.clear();
+                ties.push(kept);
+            }
+        }
+        kept += 1;
+        true
+    });
+    if ties.is_empty() {
+        return None;
+    }
+    Some(unresolved[ties[rng.gen_range(0..ties.len())]])
+}
+
+/// Observe a cell with the fewest possible states, breaking ties uniformly at
+/// random.
+#[derive(Debug, Clone, Default)]
+pub struct MinimumCount {
+    ties: Vec<usize>,
+}
+
+impl<Sp: Space<S>, S: Superposition> CollapseOrder<Sp, S> for MinimumCount {
+    fn select<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        unresolved: &mut Vec<Sp::Coordinate>,
+        space: &Sp,
+        _: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+    ) -> Option<Sp::Coordinate> {
+        select_lowest(unresolved, &mut self.ties, rng, |coord| {
+            let entropy = space[coord].entropy();
+            (entropy != 0).then_some(entropy)
+        })
+    }
+}
+
+/// Observe a cell with the lowest Shannon entropy, according to
+/// [SetCollapseObserver::weight], breaking ties with a small amount of noise.
+///
+/// The entropy of each cell is updated incrementally as states are removed,
+/// so a fresh instance is required for each [crate::Collapser].
+#[derive(Debug, Clone)]
+pub struct WeightedEntropy<C> {
+    cells: HashMap<C, CellEntropy>,
+    ties: Vec<usize>,
+}
+
+impl<C> Default for WeightedEntropy<C> {
+    fn default() -> Self {
+        Self {
+            cells: HashMap::new(),
+            ties: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CellEntropy {
+    sum_weights: f64,
+    sum_weight_log_weights: f64,
+    noise: f64,
+}
+
+impl CellEntropy {
+    fn new<C: Copy, O: SetCollapseObserver<C>, S: Superposition>(
+        coordinate: C,
+        cell: &S,
+        rule: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let mut ret = Self {
+            sum_weights: 0.0,
+            sum_weight_log_weights: 0.0,
+            noise: rng.gen::<f64>() * 1e-6,
+        };
+        for state in cell.iter() {
+            ret.update(rule.observer().weight(coordinate, state), 1.0);
+        }
+        ret
+    }
+
+    fn entropy(&self) -> f64 {
+        if self.sum_weights > 0.0 {
+            self.sum_weights.ln() - self.sum_weight_log_weights / self.sum_weights + self.noise
+        } else {
+            self.noise
+        }
+    }
+
+    fn update(&mut self, weight: f64, sign: f64) {
+        if weight > 0.0 {
+            self.sum_weights += sign * weight;
+            self.sum_weight_log_weights += sign * weight * weight.ln();
+        }
+    }
+}
+
+impl<Sp: Space<S>, S: Superposition> CollapseOrder<Sp, S> for WeightedEntropy<Sp::Coordinate> {
+    fn select<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        unresolved: &mut Vec<Sp::Coordinate>,
+        space: &Sp,
+        rule: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+    ) -> Option<Sp::Coordinate> {
+        // Cells can become unresolved again by backtracking, or be added
+        // later, and each needs its own noise.
+        for &coord in unresolved.iter() {
+            self.cells
+                .entry(coord)
+                .or_insert_with(|| CellEntropy::new(coord, &space[coord], rule, rng));
+        }
+        let cells = &self.cells;
+        select_lowest(unresolved, &mut self.ties, rng, |coord| {
+            (space[coord].entropy() != 0).then(|| cells[&coord].entropy())
+        })
+    }
+
+    fn removed<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        coordinate: Sp::Coordinate,
+        state: State,
+        rule: &SetCollapseRules<O, S>,
+    ) {
+        if let Some(entry) = self.cells.get_mut(&coordinate) {
+            entry.update(rule.observer().weight(coordinate, state), -1.0);
+        }
+    }
+
+    fn restored<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        coordinate: Sp::Coordinate,
+        state: State,
+        rule: &SetCollapseRules<O, S>,
+    ) {
+        if let Some(entry) = self.cells.get_mut(&coordinate) {
+            entry.update(rule.observer().weight(coordinate, state), 1.0);
+        }
+    }
+}
+
+/// Observe cells in the order of [Space::visit_coordinates], i.e. one row at a
+/// time for grids.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Scanline;
+
+impl<Sp: Space<S>, S: Superposition> CollapseOrder<Sp, S> for Scanline {
+    fn select<O: SetCollapseObserver<Sp::Coordinate>>(
+        &mut self,
+        unresolved: &mut Vec<Sp::Coordinate>,
+        space: &Sp,
+        _: &SetCollapseRules<O, S>,
+        _: &mut impl Rng,
+    ) -> Option<Sp::Coordinate> {
+        let resolved = unresolved
+            .iter()
+            .position(|&coord| space[coord].entropy() != 0)
+            .unwrap_or(unresolved.len());
+        unresolved.drain(..resolved);
+        unresolved.first().copied()
+    }
+}
+
+/// Observe the cell closest to `origin`, breaking ties uniformly at random,
+/// such that the result grows outward from `origin`.
+///
+/// Only implemented for grids, where distance is Euclidean for
+/// [Grid2d] and [Grid3d], and the number of steps for [GridHex]. Periodic axes
+/// don't wrap around.
+#[derive(Debug, Clone, Default)]
+pub struct Growth<C> {
+    /// Where to start.
+    pub origin: C,
+    ties: Vec<usize>,
+}
+
+impl<C: Copy> Growth<C> {
+    /// Grow outward from `origin`.
+    pub fn new(origin: C) -> Self {
+        Self {
+            origin,
+            ties: Vec::new(),
+        }
+    }
+
+    /// Select the unresolved cell with the lowest `distance` from
+    /// [Self::origin].
+    fn select_closest<Sp: Space<S, Coordinate = C>, S: Superposition>(
+        &mut self,
+        unresolved: &mut Vec<C>,
+        space: &Sp,
+        rng: &mut impl Rng,
+        distance: impl Fn(C, C) -> u64,
+    ) -> Option<C> {
+        let origin = self.origin;
+        select_lowest(unresolved, &mut self.ties, rng, |coord| {
+            (space[coord].entropy() != 0).then(|| distance(coord, origin))
+        })
+    }
+}
+
+impl<S: Superposition> CollapseOrder<Grid2d<S>, S> for Growth<Coordinate2d> {
+    fn select<O: SetCollapseObserver<Coordinate2d>>(
+        &mut self,
+        unresolved: &mut Vec<Coordinate2d>,
+        space: &Grid2d<S>,
+        _: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+    ) -> Option<Coordinate2d> {
+        self.select_closest(unresolved, space, rng, |a, b| {
+            let (dx, dy) = (a.x.abs_diff(b.x) as u64, a.y.abs_diff(b.y) as u64);
+            dx * dx + dy * dy
+        })
+    }
+}
+
+impl<S: Superposition> CollapseOrder<Grid3d<S>, S> for Growth<Coordinate3d> {
+    fn select<O: SetCollapseObserver<Coordinate3d>>(
+        &mut self,
+        unresolved: &mut Vec<Coordinate3d>,
+        space: &Grid3d<S>,
+        _: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+    ) -> Option<Coordinate3d> {
+        self.select_closest(unresolved, space, rng, |a, b| {
+            let (dx, dy, dz) = (
+                a.x.abs_diff(b.x) as u64,
+                a.y.abs_diff(b.y) as u64,
+                a.z.abs_diff(b.z) as u64,
+            );
+            dx * dx + dy * dy + dz * dz
+        })
+    }
+}
+
+impl<S: Superposition, L: Layout> CollapseOrder<GridHex<S, L>, S> for Growth<CoordinateHex> {
+    fn select<O: SetCollapseObserver<CoordinateHex>>(
+        &mut self,
+        unresolved: &mut Vec<CoordinateHex>,
+        space: &GridHex<S, L>,
+        _: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+    ) -> Option<CoordinateHex> {
+        self.select_closest(unresolved, space, rng, |a, b| {
+            (L::to_axial(a) - L::to_axial(b)).length() as u64
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CollapseOrder, Growth, Scanline, WeightedEntropy};
+    use crate::{
+        grid_2d::{Coordinate2d, Direction2d, Grid2d},
+        grid_hex::{CoordinateHex, FlatTop, GridHex},
+        rules::{SetCollapseRules, SetCollapseRulesBuilder, UniformSetCollapseObserver},
+        Space, State, StateDomain, StateSet,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Any state may be next to any other.
+    fn anything() -> SetCollapseRules<UniformSetCollapseObserver> {
+        let domain = StateDomain::new(3);
+        let mut builder =
+            SetCollapseRulesBuilder::<Grid2d<StateSet>, _>::new(domain, UniformSetCollapseObserver);
+        for state in domain.states() {
+            let neighbors = [Direction2d::Right, Direction2d::Up].map(|d| (d, domain.all()));
+            builder = builder.allow(state, &neighbors);
+        }
+        builder.build()
+    }
+
+    /// Cells of a fresh `Sp` of `dimensions` in the order `order` selects
+    /// them, resolving each as it's selected.
+    fn selection_order<Sp: Space<StateSet>, H: CollapseOrder<Sp>>(
+        dimensions: Sp::Coordinate,
+        mut order: H,
+    ) -> Vec<Sp::Coordinate> {
+        let rule = anything();
+        let mut space = Sp::new(dimensions, |_| rule.domain().all());
+        let mut unresolved = Vec::new();
+        space.visit_coordinates(|c| unresolved.push(c));
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut selected = Vec::new();
+        while let Some(c) = order.select(&mut unresolved, &space, &rule, &mut rng) {
+            for state in [1, 2].map(State::nth) {
+                space[c].remove(state);
+                order.removed(c, state, &rule);
+            }
+            selected.push(c);
+        }
+        selected
+    }
+
+    #[test]
+    fn scanline_order() {
+        let mut expected = Vec::new();
+        Grid2d::new(Coordinate2d { x: 7, y: 5 }, |_| ()).visit_coordinates(|c| expected.push(c));
+        let dimensions = Coordinate2d { x: 7, y: 5 };
+        assert_eq!(
+            selection_order::<Grid2d<_>, _>(dimensions, Scanline),
+            expected
+        );
+    }
+
+    #[test]
+    fn growth_order() {
+        let origin = Coordinate2d { x: 2, y: 3 };
+        let selected =
+            selection_order::<Grid2d<_>, _>(Coordinate2d { x: 7, y: 5 }, Growth::new(origin));
+        assert_eq!(selected.len(), 7 * 5);
+        assert_eq!(selected[0], origin);
+        let distance = |c: Coordinate2d| c.x.abs_diff(2).pow(2) + c.y.abs_diff(3).pow(2);
+        assert!(selected
+            .windows(2)
+            .all(|pair| distance(pair[0]) <= distance(pair[1])));
+    }
+
+    #[test]
+    fn growth_order_hex() {
+        let origin = CoordinateHex { x: 3, y: 3 };
+        let grid = GridHex::<(), FlatTop>::new(CoordinateHex { x: 7, y: 7 }, |_| ());
+        let selected =
+            selection_order::<GridHex<_, FlatTop>, _>(grid.dimensions(), Growth::new(origin));
+        assert_eq!(selected.len(), 7 * 7);
+        let distance = |c| (grid.to_axial(c) - grid.to_axial(origin)).length();
+        assert!(selected
+            .windows(2)
+            .all(|pair| distance(pair[0]) <= distance(pair[1])));
+        // The origin, then its neighbors, even though they are in different
+        // rows.
+        let mut neighbors = GridHex::<(), FlatTop>::DIRECTIONS
+            .iter()
+            .map(|&d| grid.neighbor(origin, d).unwrap())
+            .collect::<Vec<_>>();
+        neighbors.sort();
+        let mut first = selected[1..7].to_vec();
+        first.sort();
+        assert_eq!((selected[0], first), (origin, neighbors));
+    }
+
+    #[test]
+    fn weighted_entropy_noise_per_cell() {
+        let rule = anything();
+        let mut space = Grid2d::new(Coordinate2d { x: 4, y: 4 }, |_| rule.domain().all());
+        let mut unresolved = Vec::new();
+        space.visit_coordinates(|c| unresolved.push(c));
+        let mut order = WeightedEntropy::default();
+        let mut rng = StdRng::seed_from_u64(0);
+        let selected = order.select(&mut unresolved, &space, &rule, &mut rng);
+
+        // Every cell has the same states, so the one with the least noise is
+        // observed first.
+        let mut noise = order
+            .cells
+            .iter()
+            .map(|(&c, cell)| (cell.noise, c))
+            .collect::<Vec<_>>();
+        noise.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assert_eq!(noise.len(), 16);
+        assert!(noise.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(selected, Some(noise[0].1));
+
+        // Removing a state lowers the entropy of just that cell.
+        let c = noise[15].1;
+        space[c].remove(State::nth(0));
+        CollapseOrder::<Grid2d<_>>::removed(&mut order, c, State::nth(0), &rule);
+        assert_eq!(
+            order.select(&mut unresolved, &space, &rule, &mut rng),
+            Some(c)
+        );
+    }
+}
@@ -2,13 +2,20 @@
 
 use crate::{
     rules::{SetCollapseObserver, SetCollapseRules, SetCollapseRulesBuilder},
-    state::{State, StateSet},
+    state::{State, StateDomain, StateSet, Superposition},
     Space,
 };
 use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
-use std::{collections::HashMap, hash::Hash, num::NonZeroU32};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    num::NonZeroU32,
+};
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct Pattern<T> {
     center: Option<T>,
     frequency: u32,
@@ -31,6 +38,11 @@ pub trait Tile<F, R> {
     /// 90 degree rotation of `coordinate` around `axis` as if by looking down that axis
     /// in a left-handed coordinate system and rotating counter-clockwise. For a
     /// rotationally-symmetric block, this is a no-op.
+    ///
+    /// # Hexagonal
+    ///
+    /// 60 degree counter-clockwise rotation. For a hexagonally-symmetric tile,
+    /// this is a no-op.
     fn perp(self, axis: R) -> Self;
 }
 
@@ -49,6 +61,7 @@ impl<F, R> Tile<F, R> for NonZeroU32 {
 /// When collapsing superpositions, respects the frequency of each
 /// possible pattern.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ExtractedPatterns<T> {
     patterns: Vec<Pattern<T>>,
     density_bias: u32,
@@ -79,7 +92,7 @@ impl<T: Clone> ExtractedPatterns<T> {
         let mut overconstrained = 0;
         let ret = Osp::new(space.dimensions(), |coord| {
             let states = &space[coord];
-            for s in 0..StateSet::len() {
+            for s in 0..self.patterns.len() as u32 {
                 if states.has(State::nth(s)) {
                     return self.patterns[s as usize].center.clone();
                 }
@@ -91,27 +104,59 @@ impl<T: Clone> ExtractedPatterns<T> {
     }
 }
 
-impl<T> SetCollapseObserver for ExtractedPatterns<T> {
-    fn observe(&self, cell: &mut StateSet, _neighbors: &[Option<StateSet>], rng: &mut impl Rng) {
-        let dist = WeightedIndex::new((0..StateSet::len()).map(|s| {
+impl<T> ExtractedPatterns<T> {
+    fn pattern_weight(&self, state: State) -> u32 {
+        let pattern = &self.patterns[state.0 as usize];
+        pattern
+            .frequency
+            .saturating_mul(pattern.density.saturating_mul(self.density_bias) + 1)
+    }
+}
+
+impl<T, C> SetCollapseObserver<C> for ExtractedPatterns<T> {
+    fn observe<S: Superposition, Sp: Space<S, Coordinate = C>>(
+        &self,
+        _space: &Sp,
+        _coordinate: C,
+        cell: &mut S,
+        _neighbors: &[Option<S>],
+        rng: &mut impl Rng,
+    ) {
+        let dist = WeightedIndex::new((0..self.patterns.len() as u32).map(|s| {
             if cell.has(State::nth(s)) {
-                let pattern = &self.patterns[s as usize];
-                pattern
-                    .frequency
-                    .saturating_mul(pattern.density.saturating_mul(self.density_bias) + 1)
+                self.pattern_weight(State::nth(s))
             } else {
                 0
             }
         }))
         .unwrap();
 
-        *cell = StateSet::with_states(&[State::nth(dist.sample(rng) as u32)]);
+        *cell = S::with_states(cell.domain(), &[State::nth(dist.sample(rng) as u32)]);
+    }
+
+    fn weight(&self, _coordinate: C, state: State) -> f64 {
+        self.pattern_weight(state) as f64
+    }
+
+    fn is_coordinate_independent(&self) -> bool {
+        true
     }
 }
 
 /// Find patterns, of size `size`, in the `input`.
+///
+/// Patterns wrap around the edges of `input` along any axes where it is
+/// periodic (see e.g. [crate::grid_2d::Grid2d::with_periodic]).
+///
+/// Each pattern is flipped across each of `flip_symmetries` in turn, and on
+/// grids of quarter turns (see [Space::ROTATIONS]), each flip is rotated once
+/// by `rotational_symmetry`, which together make every symmetry of a square.
+/// Other grids (e.g. [crate::grid_hex::GridHex]) take every element of the
+/// symmetry group generated by `flip_symmetries` and `rotational_symmetry`,
+/// leaving out cells of the pattern that some symmetry moves out of it (e.g.
+/// the corners of a hexagonal pattern).
 pub fn codify_patterns<
-    T: Clone + PartialEq,
+    T: Clone + PartialEq + Tile<Sp::Axis, Sp::RotationAxis>,
     Sp: Space<Option<T>> + Hash + Eq + Clone,
     Ssp: Space<StateSet, Coordinate = Sp::Coordinate, Direction = Sp::Direction, Axis = Sp::Axis>,
 >(
@@ -119,10 +164,43 @@ pub fn codify_patterns<
     size: Sp::Coordinate,
     flip_symmetries: &[Sp::Axis],
     rotational_symmetry: Option<Sp::RotationAxis>,
-) -> SetCollapseRules<ExtractedPatterns<T>>
-where
-    T: Tile<Sp::Axis, Sp::RotationAxis>,
-{
+) -> SetCollapseRules<ExtractedPatterns<T>> {
+    let quarter_turns = Sp::ROTATIONS == 4;
+    let probe = Sp::new(size, |_| None);
+    codify_variants::<_, _, Ssp>(
+        input,
+        size,
+        None,
+        |coordinate| {
+            quarter_turns || stays_in(&probe, coordinate, flip_symmetries, rotational_symmetry)
+        },
+        |grid| {
+            if quarter_turns {
+                quarter_turn_variants(grid, size, flip_symmetries, rotational_symmetry)
+            } else {
+                variants(grid, size, flip_symmetries, rotational_symmetry)
+            }
+        },
+    )
+    .0
+}
+
+/// Find patterns, of size `size`, in the `input`, including each of their
+/// `variants`, and ignoring cells that aren't `symmetric`.
+///
+/// Also returns the state of the first variant of the pattern centered on
+/// `marked`, if any.
+pub(crate) fn codify_variants<
+    T: Clone + PartialEq,
+    Sp: Space<Option<T>> + Hash + Eq + Clone,
+    Ssp: Space<StateSet, Coordinate = Sp::Coordinate, Direction = Sp::Direction, Axis = Sp::Axis>,
+>(
+    input: &Sp,
+    size: Sp::Coordinate,
+    marked: Option<Sp::Coordinate>,
+    symmetric: impl Fn(Sp::Coordinate) -> bool,
+    variants: impl Fn(Sp) -> Vec<Sp>,
+) -> (SetCollapseRules<ExtractedPatterns<T>>, Option<State>) {
     let neg_radius = Sp::map(size, |_, c| c / 2);
     struct PatternInfo {
         index: u32,
@@ -130,9 +208,13 @@ where
         density: u32,
     }
     let mut patterns = HashMap::<Sp, PatternInfo>::new();
-    Sp::visit_coordinates(input.dimensions(), |input_coordinate| {
+    let mut marked_state = None;
+    input.visit_coordinates(|input_coordinate| {
         let mut density = 0;
-        let mut grid = Sp::new(size, |pattern_coordinate| {
+        let grid = Sp::new(size, |pattern_coordinate| {
+            if !symmetric(pattern_coordinate) {
+                return None;
+            }
             let sample_coordinate =
                 input.add_sub(input_coordinate, pattern_coordinate, neg_radius)?;
             let ret = input[sample_coordinate].clone();
@@ -140,93 +222,314 @@ where
             ret
         });
 
-        for axis in std::iter::once(None).chain(
-            flip_symmetries
-                .iter()
-                .chain(&flip_symmetries[..flip_symmetries.len().saturating_sub(1)])
-                .map(Some),
-        ) {
-            if let Some(axis) = axis {
-                let new_grid = Sp::new(size, |c| {
-                    let c = Sp::map(c, |a, c| if a == *axis { size[*axis] - 1 - c } else { c });
-
-                    grid[c].clone().map(|t| t.flip(*axis))
-                });
-                grid = new_grid;
-            }
-
-            for rotational_symmetry in std::iter::once(Option::<Sp::RotationAxis>::None)
-                .chain(rotational_symmetry.into_iter().map(Some))
-            {
-                let rotated_grid = if let Some(rotation_axis) = rotational_symmetry {
-                    Sp::new(size, |c| {
-                        let c = grid.perp(c, rotation_axis);
-
-                        grid[c].clone().map(|t| t.perp(rotation_axis))
-                    })
-                } else {
-                    grid.clone()
-                };
-
-                let next_index = patterns.len() as u32;
-                // TODO.
-                let entry = patterns.entry(rotated_grid).or_insert(PatternInfo {
-                    index: next_index,
-                    frequency: 0,
-                    density,
-                });
-                entry.frequency += 1;
+        for (i, variant) in variants(grid).into_iter().enumerate() {
+            let next_index = patterns.len() as u32;
+            let entry = patterns.entry(variant).or_insert(PatternInfo {
+                index: next_index,
+                frequency: 0,
+                density,
+            });
+            entry.frequency += 1;
+            if i == 0 && marked == Some(input_coordinate) {
+                marked_state = Some(State::nth(entry.index));
             }
         }
     });
 
-    StateSet::scope(patterns.len() as u32, || {
-        let mut extracted_patterns = vec![
-            Pattern {
-                center: None,
-                frequency: 0,
-                density: 0,
-            };
-            patterns.len()
-        ];
-        for (grid, info) in patterns.iter() {
-            extracted_patterns[info.index as usize] = Pattern {
-                frequency: info.frequency,
-                center: grid[neg_radius].clone(),
-                density: info.density,
-            };
-        }
-        let mut builder = SetCollapseRulesBuilder::<Ssp, _>::new(ExtractedPatterns {
+    let domain = StateDomain::new(patterns.len() as u32);
+    let mut extracted_patterns = vec![
+        Pattern {
+            center: None,
+            frequency: 0,
+            density: 0,
+        };
+        patterns.len()
+    ];
+    for (grid, info) in patterns.iter() {
+        extracted_patterns[info.index as usize] = Pattern {
+            frequency: info.frequency,
+            center: grid[neg_radius].clone(),
+            density: info.density,
+        };
+    }
+    let mut builder = SetCollapseRulesBuilder::<Ssp, _>::new(
+        domain,
+        ExtractedPatterns {
             patterns: extracted_patterns,
             density_bias: 0,
-        });
+        },
+    );
+
+    for (pattern, info) in patterns.iter() {
+        let mut neighbors = Vec::new();
+        for &direction in Sp::DIRECTIONS {
+            let mut allowed = domain.all();
 
-        for (pattern, info) in patterns.iter() {
-            let mut neighbors = Vec::new();
-            for &direction in Sp::DIRECTIONS {
-                let mut allowed = StateSet::all();
-
-                for (pattern2, info2) in patterns.iter() {
-                    let mut compatible = true;
-                    Sp::visit_coordinates(pattern.dimensions(), |coordinate| {
-                        let coordinate2 = pattern.neighbor(coordinate, -direction);
-                        if let Some(coordinate2) = coordinate2 {
-                            let value = pattern[coordinate].clone();
-                            let value2 = pattern2[coordinate2].clone();
-                            // TODO: early exit.
-                            compatible &= value == value2;
-                        }
-                    });
-                    if !compatible {
-                        allowed.remove(State::nth(info2.index));
+            for (pattern2, info2) in patterns.iter() {
+                let mut compatible = true;
+                pattern.visit_coordinates(|coordinate| {
+                    let coordinate2 = pattern
+                        .neighbor(coordinate, -direction)
+                        .filter(|&c| symmetric(coordinate) && symmetric(c));
+                    if let Some(coordinate2) = coordinate2 {
+                        let value = pattern[coordinate].clone();
+                        let value2 = pattern2[coordinate2].clone();
+                        // TODO: early exit.
+                        compatible &= value == value2;
                     }
+                });
+                if !compatible {
+                    allowed.remove(State::nth(info2.index));
                 }
+            }
+
+            neighbors.push((direction, allowed));
+        }
+        builder = builder.allow(State::nth(info.index), &neighbors);
+    }
+
+    (builder.build(), marked_state)
+}
+
+/// Each flip of `grid` across `flip_symmetries` in turn, and a quarter turn of
+/// each by `rotational_symmetry`.
+fn quarter_turn_variants<
+    T: Clone + PartialEq + Tile<Sp::Axis, Sp::RotationAxis>,
+    Sp: Space<Option<T>> + Clone,
+>(
+    mut grid: Sp,
+    size: Sp::Coordinate,
+    flip_symmetries: &[Sp::Axis],
+    rotational_symmetry: Option<Sp::RotationAxis>,
+) -> Vec<Sp> {
+    let mut variants = Vec::new();
+    for axis in std::iter::once(None).chain(
+        flip_symmetries
+            .iter()
+            .chain(&flip_symmetries[..flip_symmetries.len().saturating_sub(1)])
+            .map(Some),
+    ) {
+        if let Some(axis) = axis {
+            let new_grid = Sp::new(size, |c| {
+                let c = Sp::map(c, |a, c| if a == *axis { size[*axis] - 1 - c } else { c });
+
+                grid[c].clone().map(|t| t.flip(*axis))
+            });
+            grid = new_grid;
+        }
+
+        variants.push(grid.clone());
+        if let Some(rotation_axis) = rotational_symmetry {
+            variants.push(Sp::new(size, |c| {
+                let c = grid.perp(c, rotation_axis)?;
+
+                grid[c].clone().map(|t| t.perp(rotation_axis))
+            }));
+        }
+    }
+    variants
+}
+
+/// Whether every flip and rotation of the cell at `coordinate` stays in
+/// `space` (e.g. not the corners of a hexagonal pattern).
+fn stays_in<T, Sp: Space<T>>(
+    space: &Sp,
+    coordinate: Sp::Coordinate,
+    flip_symmetries: &[Sp::Axis],
+    rotational_symmetry: Option<Sp::RotationAxis>,
+) -> bool {
+    let mut orbit = vec![coordinate];
+    let mut i = 0;
+    while let Some(&c) = orbit.get(i) {
+        let images = flip_symmetries
+            .iter()
+            .map(|&axis| space.flip(c, axis))
+            .chain(rotational_symmetry.map(|axis| space.perp(c, axis)));
+        for image in images {
+            let Some(image) = image else {
+                return false;
+            };
+            if !orbit.contains(&image) {
+                orbit.push(image);
+            }
+        }
+        i += 1;
+    }
+    true
+}
+
+/// The variant of `grid` under each element of the symmetry group generated
+/// by `flip_symmetries` and `rotational_symmetry`, so a symmetric `grid`
+/// appears once per element that maps it to itself.
+fn variants<
+    T: Clone + PartialEq + Tile<Sp::Axis, Sp::RotationAxis>,
+    Sp: Space<Option<T>> + Eq + Clone,
+>(
+    mut grid: Sp,
+    size: Sp::Coordinate,
+    flip_symmetries: &[Sp::Axis],
+    rotational_symmetry: Option<Sp::RotationAxis>,
+) -> Vec<Sp> {
+    // Elements are told apart by where they move each cell of a probe that is
+    // big enough for every element to move its cells differently.
+    let probe = Sp::new(Sp::map(size, |_, _| 3), |_| None);
+    let mut element = BTreeMap::new();
+    probe.visit_coordinates(|c| {
+        if stays_in(&probe, c, flip_symmetries, rotational_symmetry) {
+            element.insert(c, c);
+        }
+    });
+    let then = |element: &BTreeMap<_, _>, f: &dyn Fn(_) -> Option<_>| {
+        element
+            .keys()
+            .map(|&c| (c, element[&f(c).unwrap()]))
+            .collect::<BTreeMap<_, _>>()
+    };
+
+    let mut elements = Vec::new();
+    let mut variants = Vec::<Sp>::new();
+    for axis in std::iter::once(None).chain(
+        flip_symmetries
+            .iter()
+            .chain(&flip_symmetries[..flip_symmetries.len().saturating_sub(1)])
+            .map(Some),
+    ) {
+        if let Some(axis) = axis {
+            let new_grid = Sp::new(size, |c| {
+                let c = grid.flip(c, *axis)?;
+
+                grid[c].clone().map(|t| t.flip(*axis))
+            });
+            grid = new_grid;
+            element = then(&element, &|c| probe.flip(c, *axis));
+        }
 
-                neighbors.push((direction, allowed));
+        let mut rotated_grid = grid.clone();
+        let mut rotated = element.clone();
+        // Grids return to the original after at most 6 rotations, but
+        // don't rely on that for other spaces.
+        for _ in 0..12 {
+            if !elements.contains(&rotated) {
+                elements.push(rotated.clone());
+                variants.push(rotated_grid.clone());
+            }
+            let Some(rotation_axis) = rotational_symmetry else {
+                break;
+            };
+            rotated_grid = Sp::new(size, |c| {
+                let c = rotated_grid.perp(c, rotation_axis)?;
+
+                rotated_grid[c].clone().map(|t| t.perp(rotation_axis))
+            });
+            rotated = then(&rotated, &|c| probe.perp(c, rotation_axis));
+            if rotated == element {
+                break;
             }
-            builder = builder.allow(State::nth(info.index), &neighbors);
         }
+    }
+    variants
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{codify_patterns, quarter_turn_variants, variants};
+    use crate::{
+        grid_2d::{Axis2d, Coordinate2d, Grid2d},
+        grid_hex::{AxisHex, CoordinateHex, GridHex, Layout, PointyTop},
+        state::StateSet,
+        Space,
+    };
+    use std::num::NonZeroU32;
 
-        builder.build()
-    })
+    #[test]
+    fn asymmetric_hex_pattern_has_12_variants() {
+        let size = CoordinateHex { x: 3, y: 3 };
+        let mut next = 0;
+        let pattern = GridHex::<_, PointyTop>::new(size, |c| {
+            let relative =
+                PointyTop::to_axial(c) - PointyTop::to_axial(CoordinateHex { x: 1, y: 1 });
+            (relative.length() <= 1).then(|| {
+                next += 1;
+                NonZeroU32::new(next).unwrap()
+            })
+        });
+        assert_eq!(next, 7);
+        let variants = variants(pattern, size, &[AxisHex::X, AxisHex::Y], Some(()));
+        assert_eq!(variants.len(), 12);
+    }
+
+    #[test]
+    fn asymmetric_square_pattern_has_8_variants() {
+        let size = Coordinate2d { x: 3, y: 3 };
+        let mut next = 0;
+        let pattern = Grid2d::new(size, |_| {
+            next += 1;
+            NonZeroU32::new(next)
+        });
+        let variants = quarter_turn_variants(pattern, size, &[Axis2d::X, Axis2d::Y], Some(()));
+        assert_eq!(variants.len(), 8);
+        assert!(variants
+            .iter()
+            .enumerate()
+            .all(|(i, variant)| !variants[..i].contains(variant)));
+    }
+
+    #[test]
+    fn symmetric_pattern_has_a_variant_per_symmetry() {
+        let size = Coordinate2d { x: 3, y: 3 };
+        let pattern = Grid2d::new(size, |_| NonZeroU32::new(1));
+        let variants =
+            quarter_turn_variants(pattern.clone(), size, &[Axis2d::X, Axis2d::Y], Some(()));
+        assert_eq!(variants.len(), 8);
+        assert!(variants.iter().all(|variant| *variant == pattern));
+    }
+
+    #[test]
+    fn square_patterns_rotate_once_per_flip() {
+        // Every 2x2 pattern of the input is distinct under every symmetry, so
+        // there is a state per pattern and variant: one quarter turn of each
+        // flip, rather than every element of the group they generate.
+        let input = Grid2d::new(Coordinate2d { x: 3, y: 3 }, |c| {
+            NonZeroU32::new(1 + c.x + 3 * c.y)
+        })
+        .with_periodic(Axis2d::X, true)
+        .with_periodic(Axis2d::Y, true);
+        let states = |flip_symmetries: &[Axis2d], rotational_symmetry| {
+            codify_patterns::<_, _, Grid2d<StateSet>>(
+                &input,
+                Coordinate2d { x: 2, y: 2 },
+                flip_symmetries,
+                rotational_symmetry,
+            )
+            .domain()
+            .len()
+        };
+        assert_eq!(states(&[], None), 9);
+        assert_eq!(states(&[], Some(())), 9 * 2);
+        assert_eq!(states(&[Axis2d::X], None), 9 * 2);
+        assert_eq!(states(&[Axis2d::X], Some(())), 9 * 4);
+        assert_eq!(states(&[Axis2d::X, Axis2d::Y], Some(())), 9 * 8);
+    }
+
+    #[test]
+    fn frequency_counts_each_symmetry() {
+        // Every 2x2 pattern of a checkerboard is one of two patterns, and half
+        // of the 8 symmetries of a square swap them.
+        let input = Grid2d::new(Coordinate2d { x: 4, y: 4 }, |c| {
+            NonZeroU32::new(1 + (c.x + c.y) % 2)
+        })
+        .with_periodic(Axis2d::X, true)
+        .with_periodic(Axis2d::Y, true);
+        let rule = codify_patterns::<_, _, Grid2d<StateSet>>(
+            &input,
+            Coordinate2d { x: 2, y: 2 },
+            &[Axis2d::X, Axis2d::Y],
+            Some(()),
+        );
+        let patterns = &rule.observer().patterns;
+        assert_eq!(patterns.len(), 2);
+        assert!(patterns
+            .iter()
+            .all(|pattern| pattern.frequency == 16 * 8 / 2));
+    }
 }
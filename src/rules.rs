@@ -1,48 +1,299 @@
 //! Collapse constraints.
 
 use crate::{
-    state::{State, StateSet},
+    state::{State, StateDomain, StateSet, Superposition},
     Space,
 };
-use rand::{seq::IteratorRandom, Rng};
-use std::marker::PhantomData;
+use rand::{
+    distributions::{WeightedError, WeightedIndex},
+    prelude::Distribution,
+    seq::IteratorRandom,
+    Rng,
+};
+#[cfg(feature = "serde")]
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    marker::PhantomData,
+    sync::Arc,
+};
+
+/// For collapsing superpositions in a space with coordinates `C`.
+pub trait SetCollapseObserver<C> {
+    /// Arbitrarily collapse a superposition, at `coordinate` in `space`, into
+    /// a single state.
+    ///
+    /// `cell` starts as a copy of `space[coordinate]`, and replaces it
+    /// afterwards.
+    fn observe<S: Superposition, Sp: Space<S, Coordinate = C>>(
+        &self,
+        space: &Sp,
+        coordinate: C,
+        cell: &mut S,
+        neighbors: &[Option<S>],
+        rng: &mut impl Rng,
+    );
+
+    /// Relative likelihood of `state` being observed at `coordinate`, used by
+    /// [crate::order::WeightedEntropy].
+    fn weight(&self, _coordinate: C, _state: State) -> f64 {
+        1.0
+    }
 
-/// For collapsing superpositions.
-pub trait SetCollapseObserver {
-    /// Arbitrarily collapse a superposition into a single state.
-    fn observe(&self, cell: &mut StateSet, neighbors: &[Option<StateSet>], rng: &mut impl Rng);
+    /// Whether [Self::observe] and [Self::weight] ignore the coordinate.
+    ///
+    /// [crate::chunks::ChunkedGenerator] and
+    /// [crate::synthesis::ModelSynthesis] collapse pieces of a larger space on
+    /// their own, passing coordinates relative to each piece, so they panic
+    /// unless this is `true`, which rules out e.g. weight maps.
+    ///
+    /// Defaults to `false`.
+    fn is_coordinate_independent(&self) -> bool {
+        false
+    }
 }
 
 /// Collapse a superposition into a uniformly-random one of its states.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct UniformSetCollapseObserver;
 
-impl SetCollapseObserver for UniformSetCollapseObserver {
-    fn observe(&self, cell: &mut StateSet, _: &[Option<StateSet>], rng: &mut impl Rng) {
-        *cell = StateSet::with_states(&[cell.iter().choose(rng).unwrap()]);
+impl<C> SetCollapseObserver<C> for UniformSetCollapseObserver {
+    fn observe<S: Superposition, Sp: Space<S, Coordinate = C>>(
+        &self,
+        _: &Sp,
+        _: C,
+        cell: &mut S,
+        _: &[Option<S>],
+        rng: &mut impl Rng,
+    ) {
+        *cell = S::with_states(cell.domain(), &[cell.iter().choose(rng).unwrap()]);
+    }
+
+    fn is_coordinate_independent(&self) -> bool {
+        true
+    }
+}
+
+/// Collapse a superposition into one of its states, chosen with probability
+/// proportional to its weight.
+///
+/// Weights must be finite and non-negative, and invalid weights panic. If
+/// every remaining state has a weight of zero, one is chosen uniformly.
+#[derive(Clone)]
+pub struct WeightedSetCollapseObserver<C> {
+    weights: Box<[f64]>,
+    #[allow(clippy::type_complexity)]
+    coordinate_weight: Option<Arc<dyn Fn(C, State) -> f64 + Send + Sync>>,
+}
+
+impl<C> WeightedSetCollapseObserver<C> {
+    /// Every state of `domain` has a weight of 1.
+    pub fn new(domain: StateDomain) -> Self {
+        Self {
+            weights: vec![1.0; domain.len() as usize].into_boxed_slice(),
+            coordinate_weight: None,
+        }
+    }
+
+    /// Set the weight of `state`.
+    pub fn with_weight(mut self, state: State, weight: f64) -> Self {
+        self.set_weight(state, weight);
+        self
+    }
+
+    /// Multiply the weight of each state by `coordinate_weight`, which is
+    /// passed the coordinate of the cell being observed.
+    ///
+    /// The product is checked whenever it is used, which panics if it is
+    /// negative, NaN, or infinite.
+    pub fn with_coordinate_weight(
+        mut self,
+        coordinate_weight: impl Fn(C, State) -> f64 + Send + Sync + 'static,
+    ) -> Self {
+        self.coordinate_weight = Some(Arc::new(coordinate_weight));
+        self
+    }
+
+    /// Set the weight of `state`.
+    ///
+    /// # Panics
+    ///
+    /// If `weight` is negative, NaN, or infinite.
+    pub fn set_weight(&mut self, state: State, weight: f64) {
+        self.weights[state.0 as usize] = checked_weight(state, weight);
+    }
+
+    /// The weight of `state`, regardless of coordinate.
+    pub fn base_weight(&self, state: State) -> f64 {
+        self.weights[state.0 as usize]
     }
 }
 
-/// Adjacency rules.
-pub struct SetCollapseRules<O: SetCollapseObserver> {
-    state_rules: Box<[Box<[Option<StateSet>]>]>,
+impl<C: Copy> SetCollapseObserver<C> for WeightedSetCollapseObserver<C> {
+    fn observe<S: Superposition, Sp: Space<S, Coordinate = C>>(
+        &self,
+        _: &Sp,
+        coordinate: C,
+        cell: &mut S,
+        _: &[Option<S>],
+        rng: &mut impl Rng,
+    ) {
+        observe_weighted(cell, |state| self.weight(coordinate, state), rng);
+    }
+
+    fn weight(&self, coordinate: C, state: State) -> f64 {
+        let weight = self.base_weight(state);
+        if let Some(coordinate_weight) = &self.coordinate_weight {
+            checked_weight(state, weight * coordinate_weight(coordinate, state))
+        } else {
+            weight
+        }
+    }
+
+    fn is_coordinate_independent(&self) -> bool {
+        self.coordinate_weight.is_none()
+    }
+}
+
+/// Collapse a superposition into one of its states, using the weights of an
+/// inner [SetCollapseObserver] multiplied by weight maps, which vary by
+/// coordinate.
+///
+/// For example, a map may make forest more likely in the north, and another
+/// may make water more likely near the south edge.
+///
+/// Weights must be finite and non-negative, and invalid weights panic. If
+/// every remaining state has a weight of zero, one is chosen uniformly.
+#[derive(Clone)]
+pub struct WeightMapSetCollapseObserver<O, C> {
+    inner: O,
+    #[allow(clippy::type_complexity)]
+    maps: Vec<Arc<dyn Fn(C, State) -> f64 + Send + Sync>>,
+}
+
+impl<O, C> WeightMapSetCollapseObserver<O, C> {
+    /// Use the weights of `inner` (see [SetCollapseObserver::weight]), without
+    /// any weight maps.
+    pub fn new(inner: O) -> Self {
+        Self {
+            inner,
+            maps: Vec::new(),
+        }
+    }
+
+    /// Multiply weights by `map`, which is passed the coordinate of the cell
+    /// being observed and the state being weighted.
+    ///
+    /// The product is checked whenever it is used, which panics if it is
+    /// negative, NaN, or infinite.
+    pub fn with_map(mut self, map: impl Fn(C, State) -> f64 + Send + Sync + 'static) -> Self {
+        self.maps.push(Arc::new(map));
+        self
+    }
+
+    /// Multiply the weight of `state` by `grid[coordinate]`. Coordinates
+    /// outside of `grid` must not be observed.
+    pub fn with_grid<G: Space<f64, Coordinate = C> + Send + Sync>(
+        self,
+        state: State,
+        grid: G,
+    ) -> Self {
+        self.with_map(move |coordinate, s| if s == state { grid[coordinate] } else { 1.0 })
+    }
+
+    /// Get the inner [SetCollapseObserver].
+    pub fn inner(&self) -> &O {
+        &self.inner
+    }
+
+    /// Mutably borrow the inner [SetCollapseObserver].
+    pub fn inner_mut(&mut self) -> &mut O {
+        &mut self.inner
+    }
+}
+
+impl<O: SetCollapseObserver<C>, C: Copy> SetCollapseObserver<C>
+    for WeightMapSetCollapseObserver<O, C>
+{
+    fn observe<S: Superposition, Sp: Space<S, Coordinate = C>>(
+        &self,
+        _: &Sp,
+        coordinate: C,
+        cell: &mut S,
+        _: &[Option<S>],
+        rng: &mut impl Rng,
+    ) {
+        observe_weighted(cell, |state| self.weight(coordinate, state), rng);
+    }
+
+    fn weight(&self, coordinate: C, state: State) -> f64 {
+        let weight = self
+            .maps
+            .iter()
+            .fold(self.inner.weight(coordinate, state), |weight, map| {
+                weight * map(coordinate, state)
+            });
+        checked_weight(state, weight)
+    }
+
+    fn is_coordinate_independent(&self) -> bool {
+        self.maps.is_empty() && self.inner.is_coordinate_independent()
+    }
+}
+
+/// Collapse `cell` into one of its states, with probability proportional to
+/// `weight`, or uniformly if no state has a positive weight.
+fn observe_weighted<S: Superposition>(
+    cell: &mut S,
+    weight: impl Fn(State) -> f64,
+    rng: &mut impl Rng,
+) {
+    let state = match WeightedIndex::new(cell.iter().map(weight)) {
+        Ok(dist) => cell.iter().nth(dist.sample(rng)),
+        Err(WeightedError::AllWeightsZero) => cell.iter().choose(rng),
+        Err(err) => panic!("cannot observe {cell:?}: {err}"),
+    };
+    *cell = S::with_states(cell.domain(), &[state.unwrap()]);
+}
+
+/// Panics unless `weight`, of `state`, is finite and non-negative.
+fn checked_weight(state: State, weight: f64) -> f64 {
+    assert!(
+        weight.is_finite() && weight >= 0.0,
+        "invalid weight {weight} for {state:?}"
+    );
+    weight
+}
+
+/// Adjacency rules, over superpositions represented as `S`.
+pub struct SetCollapseRules<O, S = StateSet> {
+    state_rules: Box<[Box<[Option<S>]>]>,
     observer: O,
 }
 
-#[derive(Clone, Default)]
-struct StateRule {
-    allowed_neighbors: Vec<Option<StateSet>>,
+#[derive(Clone)]
+struct StateRule<S> {
+    allowed_neighbors: Vec<Option<S>>,
 }
 
-impl StateRule {
-    fn add_allowed(&mut self, neighbor_index: usize, allowed: State) {
+impl<S> Default for StateRule<S> {
+    fn default() -> Self {
+        Self {
+            allowed_neighbors: Vec::new(),
+        }
+    }
+}
+
+impl<S: Superposition> StateRule<S> {
+    fn add_allowed(&mut self, domain: StateDomain, neighbor_index: usize, allowed: State) {
         while self.allowed_neighbors.len() <= neighbor_index {
             self.allowed_neighbors.push(None);
         }
         if let Some(allowed_neighbors) = &mut self.allowed_neighbors[neighbor_index] {
             allowed_neighbors.add(allowed);
         } else {
-            self.allowed_neighbors[neighbor_index] = Some(StateSet::with_states(&[allowed]));
+            self.allowed_neighbors[neighbor_index] = Some(S::with_states(domain, &[allowed]));
         }
     }
 }
@@ -50,20 +301,28 @@ impl StateRule {
 /// Builder for [SetCollapseRules]
 ///
 /// Automatically collects used coordinate deltas and manages creating symmetric rules from asymmetric definitions
-pub struct SetCollapseRulesBuilder<Sp: Space<StateSet>, O: SetCollapseObserver + Clone> {
-    state_rules: Vec<Option<StateRule>>,
+///
+/// Rules are built for superpositions represented as `S`, which must match the
+/// space, e.g. `SetCollapseRulesBuilder::<Grid2d<InlineStateSet<1>>, _, InlineStateSet<1>>`.
+pub struct SetCollapseRulesBuilder<Sp: Space<S>, O: Clone, S: Superposition = StateSet> {
+    domain: StateDomain,
+    state_rules: Vec<Option<StateRule<S>>>,
+    /// States passed to [Self::allow] that aren't in the domain.
+    out_of_range: Vec<State>,
     observer: O,
     _spooky: PhantomData<Sp>,
 }
 
-impl<Sp: Space<StateSet>, O: SetCollapseObserver + Clone> SetCollapseRulesBuilder<Sp, O>
+impl<Sp: Space<S>, O: Clone, S: Superposition> SetCollapseRulesBuilder<Sp, O, S>
 where
     Sp::Direction: Eq + Clone,
 {
-    /// Create an empty builder.
-    pub fn new(observer: O) -> Self {
+    /// Create an empty builder for rules over the states of `domain`.
+    pub fn new(domain: StateDomain, observer: O) -> Self {
         Self {
-            state_rules: vec![None; StateSet::len() as usize],
+            domain,
+            state_rules: vec![None; domain.len() as usize],
+            out_of_range: Vec::new(),
             observer,
             _spooky: PhantomData,
         }
@@ -77,39 +336,84 @@ where
     ///
     /// States which do not have any allowed neighbors for a given coordinate
     /// delta will require that those coordinates are outside of world-space.
-    pub fn allow(mut self, state: State, neighbors: &[(Sp::Direction, StateSet)]) -> Self {
+    ///
+    /// Neighbors may be any [Superposition], such as `S` or [StateSet].
+    /// States outside of the domain are ignored, and reported by
+    /// [Self::validate].
+    pub fn allow<N: Superposition>(
+        mut self,
+        state: State,
+        neighbors: &[(Sp::Direction, N)],
+    ) -> Self {
+        if !self.in_range(state) {
+            return self;
+        }
         for (delta, neighbor) in neighbors {
             for n_state in neighbor.iter() {
-                self.allow_symmetric(state, n_state, delta);
+                if self.in_range(n_state) {
+                    self.allow_symmetric(state, n_state, delta);
+                }
             }
         }
         self
     }
 
-    fn allow_symmetric(&mut self, a: State, b: State, offset: &Sp::Direction) {
-        let offset_index = self.get_offset_index(*offset);
-        self.get_rule(a).add_allowed(offset_index, b);
-        let offset_index = self.get_offset_index(-*offset);
-        self.get_rule(b).add_allowed(offset_index, a);
+    fn in_range(&mut self, state: State) -> bool {
+        let in_range = state.0 < self.domain.len();
+        if !in_range && !self.out_of_range.contains(&state) {
+            self.out_of_range.push(state);
+        }
+        in_range
     }
 
-    fn get_offset_index(&mut self, offset: Sp::Direction) -> usize {
-        for i in 0..Sp::DIRECTIONS.len() {
-            if Sp::DIRECTIONS[i] == offset {
-                return i;
-            }
-        }
-        panic!("invalid neighbor at {offset:?}");
+    fn allow_symmetric(&mut self, a: State, b: State, offset: &Sp::Direction) {
+        let domain = self.domain;
+        let offset_index = Sp::direction_index(*offset);
+        self.get_rule(a).add_allowed(domain, offset_index, b);
+        let offset_index = Sp::direction_index(-*offset);
+        self.get_rule(b).add_allowed(domain, offset_index, a);
     }
 
-    fn get_rule(&mut self, state: State) -> &mut StateRule {
+    fn get_rule(&mut self, state: State) -> &mut StateRule<S> {
         self.state_rules[state.0 as usize].get_or_insert_with(Default::default)
     }
 
+    /// Report likely mistakes in the rules so far. See [SetCollapseRules::validate].
+    pub fn validate(&self) -> Vec<RuleIssue<Sp::Direction, S>> {
+        let mut issues = self
+            .out_of_range
+            .iter()
+            .map(|&state| RuleIssue::OutOfRange {
+                state,
+                len: self.domain.len(),
+            })
+            .collect::<Vec<_>>();
+        let state_rules = Self::finish(self.state_rules.clone());
+        issues.extend(lint(self.domain, Sp::DIRECTIONS, &state_rules));
+        issues
+    }
+
     /// Build [SetCollapseRules].
-    pub fn build(self) -> SetCollapseRules<O> {
-        let mut state_rules = Vec::with_capacity(StateSet::len() as usize);
-        for proto_rule in self.state_rules {
+    ///
+    /// # Panics
+    ///
+    /// If any state passed to [Self::allow] was out of range.
+    pub fn build(self) -> SetCollapseRules<O, S> {
+        assert!(
+            self.out_of_range.is_empty(),
+            "states {:?} out of range of {} states",
+            self.out_of_range,
+            self.domain.len()
+        );
+        SetCollapseRules {
+            state_rules: Self::finish(self.state_rules),
+            observer: self.observer,
+        }
+    }
+
+    fn finish(proto_rules: Vec<Option<StateRule<S>>>) -> Box<[Box<[Option<S>]>]> {
+        let mut state_rules = Vec::with_capacity(proto_rules.len());
+        for proto_rule in proto_rules {
             if let Some(mut proto_rule) = proto_rule {
                 while proto_rule.allowed_neighbors.len() < Sp::DIRECTIONS.len() {
                     proto_rule.allowed_neighbors.push(None);
@@ -119,20 +423,150 @@ where
                 state_rules.push(vec![None; Sp::DIRECTIONS.len()].into_boxed_slice());
             }
         }
-        SetCollapseRules {
-            state_rules: state_rules.into_boxed_slice(),
-            observer: self.observer,
+        state_rules.into_boxed_slice()
+    }
+}
+
+/// Likely mistake in [SetCollapseRules], reported by
+/// [SetCollapseRules::validate] and [SetCollapseRulesBuilder::validate].
+///
+/// `D` is the direction type of the space, and `S` the superposition type of
+/// the rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleIssue<D, S = StateSet> {
+    /// `state` was passed to [SetCollapseRulesBuilder::allow], but the domain
+    /// only has `len` states.
+    OutOfRange {
+        /// The state that was out of range.
+        state: State,
+        /// Number of states in the domain.
+        len: u32,
+    },
+    /// `state` has no allowed neighbors in `direction`, so it can only be
+    /// placed where that neighbor is outside of the space.
+    NoNeighbors {
+        /// The state without neighbors.
+        state: State,
+        /// The direction without neighbors.
+        direction: D,
+    },
+    /// `state` has no allowed neighbors in any direction, so it can only be
+    /// placed in a cell without neighbors.
+    Unreachable {
+        /// The state without neighbors.
+        state: State,
+    },
+    /// `states` can never appear in an infinite space, away from its borders,
+    /// because each eventually requires a neighbor that isn't allowed. This
+    /// includes states reported as [Self::NoNeighbors] or [Self::Unreachable].
+    ///
+    /// If these are all of the states, the rules can't tile an infinite space
+    /// at all.
+    CannotTile {
+        /// States that can't appear.
+        states: S,
+    },
+}
+
+impl<D: Debug, S: Superposition> Display for RuleIssue<D, S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfRange { state, len } => {
+                write!(f, "{state:?} is out of range of {len} states")
+            }
+            Self::NoNeighbors { state, direction } => {
+                write!(f, "{state:?} has no neighbors at {direction:?}")
+            }
+            Self::Unreachable { state } => write!(f, "{state:?} has no neighbors"),
+            Self::CannotTile { states } => {
+                write!(
+                    f,
+                    "{:?} can't tile an infinite space",
+                    states.iter().collect::<Vec<_>>()
+                )
+            }
         }
     }
 }
 
-impl<O: SetCollapseObserver> SetCollapseRules<O> {
+/// Find [RuleIssue]s in `state_rules`, indexed by [State] then by index in
+/// `directions`.
+fn lint<S: Superposition, D: Copy>(
+    domain: StateDomain,
+    directions: &[D],
+    state_rules: &[Box<[Option<S>]>],
+) -> Vec<RuleIssue<D, S>> {
+    let mut issues = Vec::new();
+    if directions.is_empty() {
+        return issues;
+    }
+    let allowed = |state: State, direction: usize| {
+        state_rules[state.0 as usize][direction]
+            .as_ref()
+            .filter(|allowed| !allowed.is_empty())
+    };
+    for state in domain.states() {
+        let missing = (0..directions.len())
+            .filter(|&i| allowed(state, i).is_none())
+            .collect::<Vec<_>>();
+        if missing.len() == directions.len() {
+            issues.push(RuleIssue::Unreachable { state });
+        } else {
+            issues.extend(missing.into_iter().map(|i| RuleIssue::NoNeighbors {
+                state,
+                direction: directions[i],
+            }));
+        }
+    }
+
+    // Repeatedly remove states lacking a remaining neighbor in any direction,
+    // as if propagating constraints through a space without borders. This is
+    // necessary, but not sufficient, for tiling (which is undecidable in
+    // general).
+    let mut viable = S::all(domain);
+    loop {
+        let mut changed = false;
+        for state in domain.states() {
+            if viable.has(state)
+                && (0..directions.len())
+                    .any(|i| allowed(state, i).is_none_or(|allowed| !allowed.has_any(&viable)))
+            {
+                viable.remove(state);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    let mut states = S::all(domain);
+    states.remove_all(&viable);
+    if !states.is_empty() {
+        issues.push(RuleIssue::CannotTile { states });
+    }
+    issues
+}
+
+impl<O, S: Superposition> SetCollapseRules<O, S> {
     /// Total number of states.
     pub fn state_count(&self) -> u32 {
         self.state_rules.len() as u32
     }
 
-    pub(crate) fn collapse(&self, cell: &mut StateSet, neighbors: &[Option<StateSet>]) {
+    /// The domain of all states, for creating superpositions to fill a space
+    /// with.
+    pub fn domain(&self) -> StateDomain {
+        StateDomain::new(self.state_count())
+    }
+
+    /// Remove states from `cell` that are not allowed by `neighbors`, calling
+    /// `on_remove` for each.
+    pub(crate) fn collapse(
+        &self,
+        cell: &mut S,
+        neighbors: &[Option<S>],
+        mut on_remove: impl FnMut(State),
+    ) {
         for (state, allowed_neighbors) in self.state_rules.iter().enumerate() {
             let state = State::nth(state as u32);
             if cell.has(state) {
@@ -145,6 +579,7 @@ impl<O: SetCollapseObserver> SetCollapseRules<O> {
                         };
                         if !allow {
                             cell.remove(state);
+                            on_remove(state);
                             break;
                         }
                     }
@@ -153,14 +588,71 @@ impl<O: SetCollapseObserver> SetCollapseRules<O> {
         }
     }
 
+    /// States allowed in the direction `Sp::DIRECTIONS[direction]` of `state`,
+    /// where `direction` is from [Space::direction_index], or `None` if no
+    /// states are allowed.
+    pub fn allowed(&self, state: State, direction: usize) -> Option<&S> {
+        self.state_rules[state.0 as usize][direction].as_ref()
+    }
+
+    /// States allowed in `direction` of `state`, in a space of type `Sp`, or
+    /// `None` if no states are allowed.
+    pub fn allowed_in<Sp: Space<S>>(&self, state: State, direction: Sp::Direction) -> Option<&S> {
+        self.allowed(state, Sp::direction_index(direction))
+    }
+
+    /// Every state, direction, and the states allowed in that direction of
+    /// that state, in a space of type `Sp`.
+    ///
+    /// Directions in which no states are allowed are skipped.
+    pub fn adjacencies<Sp: Space<S>>(
+        &self,
+    ) -> impl Iterator<Item = (State, Sp::Direction, &S)> + '_ {
+        self.state_rules
+            .iter()
+            .enumerate()
+            .flat_map(|(state, rule)| {
+                Sp::DIRECTIONS
+                    .iter()
+                    .zip(rule.iter())
+                    .filter_map(move |(&direction, allowed)| {
+                        Some((State::nth(state as u32), direction, allowed.as_ref()?))
+                    })
+            })
+    }
+
     /// Collapse a state using the inner [SetCollapseObserver].
-    pub(crate) fn observe(
+    pub(crate) fn observe<Sp: Space<S>>(
         &self,
-        cell: &mut StateSet,
-        neighbors: &[Option<StateSet>],
+        space: &Sp,
+        coordinate: Sp::Coordinate,
+        cell: &mut S,
+        neighbors: &[Option<S>],
         rng: &mut impl Rng,
-    ) {
-        self.observer.observe(cell, neighbors, rng);
+    ) where
+        O: SetCollapseObserver<Sp::Coordinate>,
+    {
+        self.observer
+            .observe(space, coordinate, cell, neighbors, rng);
+    }
+
+    /// Report likely mistakes in the rules, for a space of type `Sp`, such as
+    /// states that can only be placed on its borders.
+    ///
+    /// Passing doesn't guarantee that collapse will succeed.
+    ///
+    /// # Panics
+    ///
+    /// If the rules weren't built for `Sp`.
+    pub fn validate<Sp: Space<S>>(&self) -> Vec<RuleIssue<Sp::Direction, S>> {
+        assert!(
+            self.state_rules
+                .iter()
+                .all(|rule| rule.len() == Sp::DIRECTIONS.len()),
+            "rules weren't built for {} directions",
+            Sp::DIRECTIONS.len()
+        );
+        lint(self.domain(), Sp::DIRECTIONS, &self.state_rules)
     }
 
     /// Get the inner [SetCollapseObserver].
@@ -173,3 +665,388 @@ impl<O: SetCollapseObserver> SetCollapseRules<O> {
         &mut self.observer
     }
 }
+
+/// Serialized form of [SetCollapseRules], which records the number of states
+/// so that it can be checked on load.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "SetCollapseRules")]
+struct SerializedRules<R, O> {
+    state_count: u32,
+    state_rules: R,
+    observer: O,
+}
+
+#[cfg(feature = "serde")]
+impl<O: Serialize, S: Serialize> Serialize for SetCollapseRules<O, S> {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        SerializedRules {
+            state_count: self.state_rules.len() as u32,
+            state_rules: &self.state_rules,
+            observer: &self.observer,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// # Errors
+///
+/// If the number of rules, or the number of states in any of them, doesn't
+/// match the number of states.
+#[cfg(feature = "serde")]
+impl<'de, O: Deserialize<'de>, S: Superposition + Deserialize<'de>> Deserialize<'de>
+    for SetCollapseRules<O, S>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let SerializedRules {
+            state_count,
+            state_rules,
+            observer,
+        } = SerializedRules::<Box<[Box<[Option<S>]>]>, O>::deserialize(deserializer)?;
+        if state_rules.len() != state_count as usize {
+            return Err(D::Error::custom(format!(
+                "{} rules for {state_count} states",
+                state_rules.len()
+            )));
+        }
+        let directions = state_rules.first().map_or(0, |rule| rule.len());
+        for rule in state_rules.iter() {
+            if rule.len() != directions {
+                return Err(D::Error::custom("inconsistent number of directions"));
+            }
+            for allowed in rule.iter().flatten() {
+                if allowed.domain().len() != state_count {
+                    return Err(D::Error::custom(format!(
+                        "rule over {} states for {state_count} states",
+                        allowed.domain().len()
+                    )));
+                }
+            }
+        }
+        Ok(Self {
+            state_rules,
+            observer,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        RuleIssue, SetCollapseObserver, SetCollapseRules, SetCollapseRulesBuilder,
+        UniformSetCollapseObserver, WeightMapSetCollapseObserver, WeightedSetCollapseObserver,
+    };
+    #[cfg(feature = "serde")]
+    use crate::{collapse, sockets::tests::pipes, verify::tests::assert_valid};
+    use crate::{
+        graph::{Graph, Port},
+        grid_2d::{Coordinate2d, Direction2d, Grid2d},
+        grid_3d::Grid3d,
+        grid_hex::{FlatTop, GridHex},
+        InlineStateSet, Space, State, StateDomain, StateSet, Superposition,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// How many times each of 3 states is chosen when observing the cell at
+    /// `coordinate` 1000 times.
+    fn observations<O: SetCollapseObserver<Coordinate2d>>(
+        observer: &O,
+        coordinate: Coordinate2d,
+    ) -> [u32; 3] {
+        let domain = StateDomain::new(3);
+        let space = Grid2d::new(Coordinate2d { x: 4, y: 4 }, |_| domain.all());
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = [0; 3];
+        for _ in 0..1000 {
+            let mut cell = domain.all();
+            observer.observe(&space, coordinate, &mut cell, &[], &mut rng);
+            assert_eq!(cell.entropy(), 0);
+            counts[cell.iter().next().unwrap().0 as usize] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn weighted_observer() {
+        let domain = StateDomain::new(3);
+        let observer = WeightedSetCollapseObserver::new(domain)
+            .with_weight(State::nth(0), 6.0)
+            .with_weight(State::nth(1), 3.0)
+            .with_weight(State::nth(2), 0.0);
+        let [a, b, c] = observations(&observer, Coordinate2d { x: 1, y: 1 });
+        assert!(a > b * 3 / 2 && b > 0, "{a} {b}");
+        assert_eq!(c, 0);
+
+        // State 1 is 4 times as likely in the first column.
+        let observer = observer.with_coordinate_weight(|coordinate: Coordinate2d, state| {
+            if coordinate.x == 0 && state == State::nth(1) {
+                4.0
+            } else {
+                1.0
+            }
+        });
+        let [a, b, _] = observations(&observer, Coordinate2d { x: 0, y: 1 });
+        assert!(b > a * 3 / 2 && a > 0, "{a} {b}");
+        assert_eq!(
+            observer.weight(Coordinate2d { x: 0, y: 1 }, State::nth(1)),
+            12.0
+        );
+        assert_eq!(observer.base_weight(State::nth(1)), 3.0);
+
+        // Uniform when every weight is zero.
+        let mut observer = WeightedSetCollapseObserver::new(domain);
+        for state in domain.states() {
+            observer.set_weight(state, 0.0);
+        }
+        let counts = observations(&observer, Coordinate2d::default());
+        assert!(counts.iter().all(|&count| count > 250), "{counts:?}");
+    }
+
+    #[test]
+    #[should_panic]
+    fn weighted_observer_rejects_negative_weights() {
+        let _ = WeightedSetCollapseObserver::<Coordinate2d>::new(StateDomain::new(3))
+            .with_weight(State::nth(0), -1.0);
+    }
+
+    #[test]
+    fn weight_maps() {
+        // State 0 only in the right half, and never state 2.
+        let grid = Grid2d::new(Coordinate2d { x: 4, y: 4 }, |c| (c.x >= 2) as u32 as f64);
+        let observer = WeightMapSetCollapseObserver::new(UniformSetCollapseObserver)
+            .with_grid(State::nth(0), grid)
+            .with_map(|_, state| if state == State::nth(2) { 0.0 } else { 1.0 });
+        let [a, b, c] = observations(&observer, Coordinate2d { x: 1, y: 2 });
+        assert_eq!((a, b, c), (0, 1000, 0));
+        let [a, b, c] = observations(&observer, Coordinate2d { x: 3, y: 2 });
+        assert!(a > 400 && b > 400, "{a} {b}");
+        assert_eq!(c, 0);
+
+        // Maps multiply the inner weights, and each other.
+        let inner =
+            WeightedSetCollapseObserver::new(StateDomain::new(3)).with_weight(State::nth(1), 2.0);
+        let observer = WeightMapSetCollapseObserver::new(inner)
+            .with_map(|c: Coordinate2d, _| c.x as f64)
+            .with_map(|c: Coordinate2d, _| c.y as f64);
+        let at = Coordinate2d { x: 2, y: 3 };
+        assert_eq!(observer.weight(at, State::nth(0)), 6.0);
+        assert_eq!(observer.weight(at, State::nth(1)), 12.0);
+        assert_eq!(observer.inner().base_weight(State::nth(1)), 2.0);
+    }
+
+    /// Rules over a single state, with an observer that depends on
+    /// coordinates.
+    pub(crate) fn coordinate_dependent<Sp: Space<StateSet>>(
+    ) -> SetCollapseRules<WeightMapSetCollapseObserver<UniformSetCollapseObserver, Sp::Coordinate>>
+    {
+        let observer =
+            WeightMapSetCollapseObserver::new(UniformSetCollapseObserver).with_map(|_, _| 1.0);
+        SetCollapseRulesBuilder::<Sp, _>::new(StateDomain::new(1), observer).build()
+    }
+
+    #[test]
+    fn coordinate_independence() {
+        let domain = StateDomain::new(3);
+        let weighted = WeightedSetCollapseObserver::<Coordinate2d>::new(domain);
+        let weight_map = WeightMapSetCollapseObserver::new(weighted.clone());
+        assert!(
+            SetCollapseObserver::<Coordinate2d>::is_coordinate_independent(
+                &UniformSetCollapseObserver
+            )
+        );
+        assert!(weighted.is_coordinate_independent());
+        assert!(weight_map.is_coordinate_independent());
+
+        let map = |_: Coordinate2d, _| 1.0;
+        assert!(!weighted
+            .clone()
+            .with_coordinate_weight(map)
+            .is_coordinate_independent());
+        assert!(!weight_map.with_map(map).is_coordinate_independent());
+        assert!(
+            !WeightMapSetCollapseObserver::new(weighted.with_coordinate_weight(map))
+                .is_coordinate_independent()
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn weight_maps_reject_negative_products() {
+        let observer = WeightMapSetCollapseObserver::new(UniformSetCollapseObserver)
+            .with_map(|_: Coordinate2d, _| -1.0);
+        observer.weight(Coordinate2d::default(), State::nth(0));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn collapse_serde() {
+        let rule = pipes::<Grid2d<StateSet>, _>();
+        let rule: SetCollapseRules<UniformSetCollapseObserver> =
+            serde_json::from_str(&serde_json::to_string(&rule).unwrap()).unwrap();
+        let mut space = Grid2d::new(Coordinate2d { x: 8, y: 6 }, |_| rule.domain().all());
+        collapse(&mut space, &rule, &mut StdRng::seed_from_u64(0), |_| {}).unwrap();
+        let space: Grid2d<StateSet> =
+            serde_json::from_str(&serde_json::to_string(&space).unwrap()).unwrap();
+        assert_valid(&space, &rule);
+    }
+
+    #[test]
+    fn validate_reports_issues() {
+        // States 0 and 1 tile the plane, 2 only appears left of 0, 3 has no
+        // neighbors, and 7 is a typo.
+        let domain = StateDomain::new(4);
+        let pair = InlineStateSet::<1>::with_states(domain, &[State::nth(0), State::nth(1)]);
+        let neighbors = [Direction2d::Right, Direction2d::Up].map(|d| (d, pair));
+        let builder = || {
+            SetCollapseRulesBuilder::<Grid2d<InlineStateSet<1>>, _, InlineStateSet<1>>::new(
+                domain,
+                UniformSetCollapseObserver,
+            )
+            .allow(State::nth(0), &neighbors)
+            .allow(State::nth(1), &neighbors)
+            .allow(
+                State::nth(2),
+                &[(Direction2d::Right, domain.with_states(&[State::nth(0)]))],
+            )
+        };
+        let issues = builder().allow(State::nth(7), &neighbors).validate();
+        let cannot_tile = InlineStateSet::with_states(domain, &[State::nth(2), State::nth(3)]);
+        assert_eq!(
+            issues,
+            [
+                RuleIssue::OutOfRange {
+                    state: State::nth(7),
+                    len: 4
+                },
+                RuleIssue::NoNeighbors {
+                    state: State::nth(2),
+                    direction: Direction2d::Up
+                },
+                RuleIssue::NoNeighbors {
+                    state: State::nth(2),
+                    direction: Direction2d::Left
+                },
+                RuleIssue::NoNeighbors {
+                    state: State::nth(2),
+                    direction: Direction2d::Down
+                },
+                RuleIssue::Unreachable {
+                    state: State::nth(3)
+                },
+                RuleIssue::CannotTile {
+                    states: cannot_tile
+                },
+            ]
+        );
+        assert_eq!(
+            issues.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "State(7) is out of range of 4 states",
+                "State(2) has no neighbors at Up",
+                "State(2) has no neighbors at Left",
+                "State(2) has no neighbors at Down",
+                "State(3) has no neighbors",
+                "[State(2), State(3)] can't tile an infinite space",
+            ]
+        );
+
+        // The built rules report the same, apart from the typo.
+        let rules = builder().build();
+        assert_eq!(rules.validate::<Grid2d<_>>(), issues[1..]);
+    }
+
+    #[test]
+    fn validate_follows_chains() {
+        // 0 must be right of 1, which must be right of 2, which can't be
+        // right of anything (and nothing can be right of 0), so none of them
+        // tile.
+        let domain = StateDomain::new(3);
+        let right_of = |state| [(Direction2d::Left, domain.with_states(&[State::nth(state)]))];
+        let above_any = [(Direction2d::Up, domain.all())];
+        let rules =
+            SetCollapseRulesBuilder::<Grid2d<StateSet>, _>::new(domain, UniformSetCollapseObserver)
+                .allow(State::nth(0), &right_of(1))
+                .allow(State::nth(1), &right_of(2))
+                .allow(State::nth(0), &above_any)
+                .allow(State::nth(1), &above_any)
+                .allow(State::nth(2), &above_any)
+                .build();
+        let issues = rules.validate::<Grid2d<_>>();
+        assert_eq!(
+            issues,
+            [
+                RuleIssue::NoNeighbors {
+                    state: State::nth(0),
+                    direction: Direction2d::Right
+                },
+                RuleIssue::NoNeighbors {
+                    state: State::nth(2),
+                    direction: Direction2d::Left
+                },
+                RuleIssue::CannotTile {
+                    states: domain.all()
+                },
+            ]
+        );
+
+        // Letting 2 follow 0 closes the loop.
+        let rules =
+            SetCollapseRulesBuilder::<Grid2d<StateSet>, _>::new(domain, UniformSetCollapseObserver)
+                .allow(State::nth(0), &right_of(1))
+                .allow(State::nth(1), &right_of(2))
+                .allow(State::nth(2), &right_of(0))
+                .allow(State::nth(0), &above_any)
+                .allow(State::nth(1), &above_any)
+                .allow(State::nth(2), &above_any)
+                .build();
+        assert_eq!(rules.validate::<Grid2d<_>>(), []);
+    }
+
+    /// [Space::direction_index] is the inverse of indexing [Space::DIRECTIONS].
+    fn assert_direction_indices<Sp: Space<StateSet>>() {
+        for (i, &direction) in Sp::DIRECTIONS.iter().enumerate() {
+            assert_eq!(Sp::direction_index(direction), i);
+            assert_eq!(Sp::DIRECTIONS[Sp::direction_index(-direction)], -direction);
+        }
+    }
+
+    #[test]
+    fn direction_indices() {
+        assert_direction_indices::<Grid2d<_>>();
+        assert_direction_indices::<Grid3d<_>>();
+        assert_direction_indices::<GridHex<_>>();
+        assert_direction_indices::<GridHex<_, FlatTop>>();
+        assert_direction_indices::<Graph<_, Port<6>>>();
+    }
+
+    #[test]
+    fn allowed_neighbors() {
+        // 1 may be right of 0, and above 1.
+        let domain = StateDomain::new(2);
+        let [zero, one] = [0, 1].map(|state| domain.with_states(&[State::nth(state)]));
+        let rules =
+            SetCollapseRulesBuilder::<Grid2d<_>, _>::new(domain, UniformSetCollapseObserver)
+                .allow(State::nth(0), &[(Direction2d::Right, one.clone())])
+                .allow(State::nth(1), &[(Direction2d::Up, one.clone())])
+                .build();
+        let allowed_in =
+            |state, direction| rules.allowed_in::<Grid2d<_>>(State::nth(state), direction);
+        assert_eq!(allowed_in(0, Direction2d::Right), Some(&one));
+        assert_eq!(allowed_in(0, Direction2d::Up), None);
+        assert_eq!(allowed_in(1, Direction2d::Left), Some(&zero));
+        assert_eq!(allowed_in(1, Direction2d::Down), Some(&one));
+        for (i, &direction) in Grid2d::<StateSet>::DIRECTIONS.iter().enumerate() {
+            assert_eq!(rules.allowed(State::nth(1), i), allowed_in(1, direction));
+        }
+
+        assert_eq!(
+            rules.adjacencies::<Grid2d<_>>().collect::<Vec<_>>(),
+            [
+                (State::nth(0), Direction2d::Right, &one),
+                (State::nth(1), Direction2d::Up, &one),
+                (State::nth(1), Direction2d::Left, &zero),
+                (State::nth(1), Direction2d::Down, &one),
+            ]
+        );
+    }
+}
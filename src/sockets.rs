@@ -0,0 +1,178 @@
+//! Socket-based rules.
+
+use crate::{
+    rules::{SetCollapseRules, SetCollapseRulesBuilder},
+    state::{State, StateDomain, StateSet, Superposition},
+    Space,
+};
+use std::{collections::HashMap, hash::Hash, marker::PhantomData};
+
+/// Builder for [SetCollapseRules], where each state declares a socket `K`
+/// (such as an edge label) per direction, and states are allowed to neighbor
+/// each other wherever their facing sockets match.
+///
+/// By default, sockets are symmetric and only match themselves. Asymmetric
+/// sockets, which only match their mirror image, are declared with
+/// [`SocketRulesBuilder::with_flipped`].
+///
+/// States which do not have a socket in a given direction will require that
+/// the neighbor in that direction is outside of world-space.
+pub struct SocketRulesBuilder<Sp: Space<S>, K, O: Clone, S: Superposition = StateSet> {
+    domain: StateDomain,
+    /// Indexed by [`State`], then by index in [`Space::DIRECTIONS`].
+    sockets: Vec<Vec<Option<K>>>,
+    flipped: HashMap<K, K>,
+    observer: O,
+    _spooky: PhantomData<(Sp, S)>,
+}
+
+impl<Sp: Space<S>, K: Eq + Hash + Clone, O: Clone, S: Superposition> SocketRulesBuilder<Sp, K, O, S>
+where
+    Sp::Direction: Eq + Clone,
+{
+    /// Create a builder for rules over the states of `domain`, none of which
+    /// have any sockets.
+    pub fn new(domain: StateDomain, observer: O) -> Self {
+        Self {
+            domain,
+            sockets: vec![vec![None; Sp::DIRECTIONS.len()]; domain.len() as usize],
+            flipped: HashMap::new(),
+            observer,
+            _spooky: PhantomData,
+        }
+    }
+
+    /// Set the socket of `state` in each of the given directions.
+    pub fn with_sockets(mut self, state: State, sockets: &[(Sp::Direction, K)]) -> Self {
+        for (direction, socket) in sockets {
+            let index = Sp::direction_index(*direction);
+            self.sockets[state.0 as usize][index] = Some(socket.clone());
+        }
+        self
+    }
+
+    /// Make `socket` and `flipped` mirror images of an asymmetric socket, such
+    /// that each matches only the other (e.g. "water-left" and
+    /// "water-right").
+    ///
+    /// If `socket == flipped`, the socket is symmetric, which is the default.
+    /// Either socket stops matching whatever it was previously flipped with.
+    pub fn with_flipped(mut self, socket: K, flipped: K) -> Self {
+        for key in [&socket, &flipped] {
+            if let Some(previous) = self.flipped.remove(key) {
+                self.flipped.remove(&previous);
+            }
+        }
+        if socket != flipped {
+            self.flipped.insert(socket.clone(), flipped.clone());
+            self.flipped.insert(flipped, socket);
+        }
+        self
+    }
+
+    /// Whether `socket` may face `other`.
+    pub fn matches(&self, socket: &K, other: &K) -> bool {
+        self.flipped.get(socket).unwrap_or(socket) == other
+    }
+
+    /// Build [SetCollapseRules], allowing every pair of states with matching
+    /// sockets.
+    pub fn build(self) -> SetCollapseRules<O, S> {
+        let opposite = Sp::DIRECTIONS
+            .iter()
+            .map(|&direction| Sp::direction_index(-direction))
+            .collect::<Vec<_>>();
+        let mut rules = Vec::with_capacity(self.sockets.len());
+        for (state, sockets) in self.sockets.iter().enumerate() {
+            let mut neighbors = Vec::new();
+            for (i, socket) in sockets.iter().enumerate() {
+                let Some(socket) = socket else {
+                    continue;
+                };
+                let mut allowed = self.domain.empty();
+                for (neighbor, neighbor_sockets) in self.sockets.iter().enumerate() {
+                    if neighbor_sockets[opposite[i]]
+                        .as_ref()
+                        .is_some_and(|other| self.matches(socket, other))
+                    {
+                        allowed.add(State::nth(neighbor as u32));
+                    }
+                }
+                neighbors.push((Sp::DIRECTIONS[i], allowed));
+            }
+            rules.push((State::nth(state as u32), neighbors));
+        }
+        let mut builder = SetCollapseRulesBuilder::<Sp, O, S>::new(self.domain, self.observer);
+        for (state, neighbors) in rules {
+            builder = builder.allow(state, &neighbors);
+        }
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::SocketRulesBuilder;
+    use crate::{
+        grid_2d::{Direction2d, Grid2d},
+        rules::{SetCollapseRules, UniformSetCollapseObserver},
+        Space, State, StateDomain, StateSet, Superposition,
+    };
+
+    /// Pipes leaving some sides of each cell, which must line up with the
+    /// pipes of its neighbors, with one state per combination of sides.
+    pub(crate) fn pipes<Sp: Space<S>, S: Superposition>(
+    ) -> SetCollapseRules<UniformSetCollapseObserver, S> {
+        let domain = StateDomain::new(1 << Sp::DIRECTIONS.len());
+        let mut builder =
+            SocketRulesBuilder::<Sp, bool, _, S>::new(domain, UniformSetCollapseObserver);
+        for state in domain.states() {
+            let sockets = Sp::DIRECTIONS
+                .iter()
+                .enumerate()
+                .map(|(i, &direction)| (direction, state.0 & (1 << i) != 0))
+                .collect::<Vec<_>>();
+            builder = builder.with_sockets(state, &sockets);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn flipped_sockets_match_each_other() {
+        use Direction2d::*;
+        let domain = StateDomain::new(4);
+        let builder =
+            SocketRulesBuilder::<Grid2d<StateSet>, _, _>::new(domain, UniformSetCollapseObserver)
+                .with_flipped("in", "out")
+                .with_sockets(State::nth(0), &[(Left, "plain"), (Right, "in")])
+                .with_sockets(State::nth(1), &[(Left, "out"), (Right, "plain")])
+                .with_sockets(State::nth(2), &[(Left, "in"), (Right, "in")])
+                .with_sockets(State::nth(3), &[(Left, "plain"), (Right, "plain")]);
+        assert!(builder.matches(&"in", &"out"));
+        assert!(builder.matches(&"out", &"in"));
+        assert!(!builder.matches(&"in", &"in"));
+        assert!(builder.matches(&"plain", &"plain"));
+        // Flipping again replaces the previous flip of either socket.
+        let reflipped = builder.with_flipped("in", "across");
+        assert!(reflipped.matches(&"in", &"across"));
+        assert!(reflipped.matches(&"across", &"in"));
+        assert!(!reflipped.matches(&"out", &"in"));
+        assert!(reflipped.matches(&"out", &"out"));
+        let builder = reflipped.with_flipped("in", "out");
+
+        let rule = builder.build();
+        let right = |state| {
+            let allowed = rule.allowed_in::<Grid2d<StateSet>>(State::nth(state), Right);
+            allowed.map(|allowed| allowed.iter().map(|s| s.0).collect::<Vec<_>>())
+        };
+        assert_eq!(right(0), Some(vec![1]));
+        assert_eq!(right(1), Some(vec![0, 3]));
+        assert_eq!(right(2), Some(vec![1]));
+        assert_eq!(right(3), Some(vec![0, 3]));
+        // Nothing has an "out" socket on its right to match the left of 2.
+        let left = rule.allowed_in::<Grid2d<StateSet>>(State::nth(2), Left);
+        assert_eq!(left, None);
+        // Without a socket, a cell must be at the edge.
+        assert_eq!(rule.allowed_in::<Grid2d<StateSet>>(State::nth(0), Up), None);
+    }
+}
@@ -13,10 +13,13 @@ use std::{
 /// In order to support arbitrary dimension and shape, two associated types are
 /// defined:
 /// - `Coordinate` is the index type for this space. Cells in the space are
-/// uniquely identified by coordinates.
-/// - `CoordinateDelta` represents adjacency relations between cells. In
-/// general, a collapse rule supplies a list of coordinate deltas to get
-/// neighbor cell coordinates.
+///   uniquely identified by coordinates.
+/// - `Direction` represents adjacency relations between cells. Each cell has
+///   at most one neighbor in each direction, and the cell's neighbor in the
+///   opposite (negated) direction of that neighbor must be the original cell.
+///
+/// Spaces need not be grids (see e.g. [crate::graph::Graph]), in which case
+/// translation, rotation, and flipping may be unsupported.
 pub trait Space<T>: IndexMut<Self::Coordinate, Output = T> + 'static {
     /// Coordinates for cells in the space
     type Coordinate: Default + Copy + Hash + Ord + Index<Self::Axis, Output = u32>;
@@ -30,10 +33,17 @@ pub trait Space<T>: IndexMut<Self::Coordinate, Output = T> + 'static {
     /// All possible values of [Space::Direction].
     const DIRECTIONS: &'static [Self::Direction];
 
-    /// The grid will occupy `(0,0,0)..dimensions`.
+    /// Number of times [Space::perp] must be applied to a coordinate to
+    /// return to it, e.g. 4 for square grids.
+    const ROTATIONS: u32 = 4;
+
+    /// For grids, the grid will occupy `(0,0,0)..dimensions`. Other spaces
+    /// may have additional structure, such as edges, that `dimensions` alone
+    /// can't describe.
     fn new(dimensions: Self::Coordinate, init_fn: impl FnMut(Self::Coordinate) -> T) -> Self;
 
-    /// The dimensions passed to [Space::new] during construction.
+    /// The dimensions of the space, such that [Space::new] would create a
+    /// space with the same coordinates.
     fn dimensions(&self) -> Self::Coordinate;
 
     /// Apply `map_fn` to each component of `coordinate`.
@@ -44,7 +54,28 @@ pub trait Space<T>: IndexMut<Self::Coordinate, Output = T> + 'static {
 
     /// 90 degree rotation of `coordinate` around `axis` as if by looking down that axis
     /// in a left-handed coordinate system and rotating counter-clockwise.
-    fn perp(&self, coordinate: Self::Coordinate, axis: Self::RotationAxis) -> Self::Coordinate;
+    ///
+    /// Returns `None` if the rotated coordinate would not be in the space (for
+    /// example, the corners of a hexagonal grid).
+    fn perp(
+        &self,
+        coordinate: Self::Coordinate,
+        axis: Self::RotationAxis,
+    ) -> Option<Self::Coordinate>;
+
+    /// Reflection of `coordinate` across the middle of `axis`.
+    ///
+    /// Returns `None` if the reflected coordinate would not be in the space.
+    fn flip(&self, coordinate: Self::Coordinate, axis: Self::Axis) -> Option<Self::Coordinate> {
+        let dimensions = self.dimensions();
+        Some(Self::map(coordinate, |a, c| {
+            if a == axis {
+                dimensions[a] - 1 - c
+            } else {
+                c
+            }
+        }))
+    }
 
     /// Computes `start + add - sub`, returning `Some` if the result is in the space.
     fn add_sub(
@@ -55,11 +86,38 @@ pub trait Space<T>: IndexMut<Self::Coordinate, Output = T> + 'static {
     ) -> Option<Self::Coordinate>;
 
     /// Get every valid coordinate in the space.
-    fn visit_coordinates(dimensions: Self::Coordinate, visitor: impl FnMut(Self::Coordinate));
+    fn visit_coordinates(&self, visitor: impl FnMut(Self::Coordinate));
     /// Get the neighbor coordinates of a given cell based on a direction.
     fn neighbor(
         &self,
         coord: Self::Coordinate,
         direction: Self::Direction,
     ) -> Option<Self::Coordinate>;
+
+    /// Index of `direction` in [Space::DIRECTIONS], by which rules and
+    /// neighbors are ordered.
+    ///
+    /// # Panics
+    ///
+    /// If `direction` isn't in [Space::DIRECTIONS].
+    fn direction_index(direction: Self::Direction) -> usize {
+        Self::DIRECTIONS
+            .iter()
+            .position(|&d| d == direction)
+            .unwrap_or_else(|| panic!("invalid neighbor at {direction:?}"))
+    }
+}
+
+/// Computes `start + delta` along an axis of length `len`, wrapping around if
+/// `periodic` and otherwise returning `None` if out of bounds. An axis of
+/// length 0 has no cells to wrap around to.
+pub(crate) fn offset(start: u32, delta: i64, len: u32, periodic: bool) -> Option<u32> {
+    let ret = start as i64 + delta;
+    if periodic && len > 0 {
+        Some(ret.rem_euclid(len as i64) as u32)
+    } else if (0..len as i64).contains(&ret) {
+        Some(ret as u32)
+    } else {
+        None
+    }
 }
@@ -1,17 +1,24 @@
 use bit_vec::BitVec;
-use std::ops::{BitAnd, BitOr, BitXor};
-use std::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "serde")]
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt::Debug,
+    hash::Hash,
+    ops::{BitAnd, BitOr, BitXor},
+};
 
 type B = u64;
 
 /// A superposition of multiple [State]'s.
 ///
-/// You must use [Self::scope] to set the total number of states.
+/// Created from a [StateDomain], such as [crate::rules::SetCollapseRules::domain],
+/// and carries its own number of states.
 #[derive(PartialEq, Eq, Clone, Hash, Debug)]
 pub struct StateSet(BitVec<B>);
 
 /// One possible state at a location.
 #[derive(PartialEq, Eq, Copy, Clone, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
 pub struct State(pub(crate) u32);
 
 impl State {
@@ -21,57 +28,143 @@ impl State {
     }
 }
 
-thread_local! {
-    static STATE_COUNT: AtomicU32 = const { AtomicU32::new(u32::MAX) };
+/// A representation of a superposition of [State]'s, which spaces, rules, and
+/// [crate::collapse] are generic over.
+///
+/// [StateSet] can represent any number of states, whereas [InlineStateSet]
+/// avoids allocating for small numbers of states.
+pub trait Superposition: Clone + Eq + Hash + Debug + 'static {
+    /// Superposition of no states of `domain`.
+    fn empty(domain: StateDomain) -> Self;
+
+    /// Superposition of all states of `domain`.
+    fn all(domain: StateDomain) -> Self {
+        let mut ret = Self::empty(domain);
+        for state in domain.states() {
+            ret.add(state);
+        }
+        ret
+    }
+
+    /// Creates a superposition of `states` of `domain`.
+    fn with_states(domain: StateDomain, states: &[State]) -> Self {
+        let mut ret = Self::empty(domain);
+        for &state in states {
+            ret.add(state);
+        }
+        ret
+    }
+
+    /// The domain this superposition was created from.
+    fn domain(&self) -> StateDomain;
+
+    /// Total number of possible states, minus 1.
+    fn entropy(&self) -> u32;
+
+    /// Are there no possible states left (a contradiction)?
+    fn is_empty(&self) -> bool;
+
+    /// Is `state` within the superposition?
+    fn has(&self, state: State) -> bool;
+
+    /// Are any of `states` within the superposition?
+    fn has_any(&self, states: &Self) -> bool;
+
+    /// Number of states in both `self` and `states`.
+    fn count_common(&self, states: &Self) -> u32;
+
+    /// Remove `state` from the superposition.
+    fn remove(&mut self, state: State);
+
+    /// Add `state` to the superposition.
+    fn add(&mut self, state: State);
+
+    /// Iterate the states within the superposition.
+    fn iter(&self) -> impl Iterator<Item = State> + '_;
+
+    /// Remove all `states` from the superposition.
+    fn remove_all(&mut self, states: &Self) {
+        for state in states.iter() {
+            self.remove(state);
+        }
+    }
+
+    /// Add all `states` to the superposition.
+    fn add_all(&mut self, states: &Self) {
+        for state in states.iter() {
+            self.add(state);
+        }
+    }
+
+    /// Filter states in place.
+    fn retain(&mut self, mut filter: impl FnMut(State) -> bool) {
+        for state in self.domain().states() {
+            if self.has(state) && !filter(state) {
+                self.remove(state);
+            }
+        }
+    }
 }
 
-fn state_count() -> u32 {
-    STATE_COUNT.with(|count| {
-        let loaded = count.load(Ordering::Relaxed);
-        debug_assert_ne!(
-            loaded,
-            u32::MAX,
-            "all StateSet's must be constructed within StateSet::scope"
-        );
-        loaded
-    })
+/// The total number of states, from which [StateSet]'s are created.
+#[derive(PartialEq, Eq, Copy, Clone, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct StateDomain {
+    len: u32,
 }
 
-impl StateSet {
-    /// All [`StateSet`]'s created in `scope` will have `state_count` states.
-    pub fn scope<R>(state_count: u32, scope: impl FnOnce() -> R) -> R {
-        STATE_COUNT.with(|count| {
-            #[cfg(debug_assertions)]
-            let old = count.load(Ordering::Relaxed);
-            count.store(state_count, Ordering::Relaxed);
-            let ret = scope();
-            #[cfg(debug_assertions)]
-            count.store(old, Ordering::Relaxed);
-            ret
-        })
+impl StateDomain {
+    /// A domain of `len` states, `State::nth(0)..State::nth(len)`.
+    pub fn new(len: u32) -> Self {
+        Self { len }
+    }
+
+    /// The total number of states.
+    #[inline(always)]
+    pub fn len(self) -> u32 {
+        self.len
+    }
+
+    /// Are there no states?
+    #[inline(always)]
+    pub fn is_empty(self) -> bool {
+        self.len == 0
+    }
+
+    /// Every state in the domain.
+    pub fn states(self) -> impl Iterator<Item = State> {
+        (0..self.len).map(State::nth)
     }
 
     /// Creates a superposition of `states`.
-    pub fn with_states(states: &[State]) -> Self {
-        let mut ret = BitVec::<B>::default();
-        ret.grow(state_count() as usize, false);
-        for state in states {
-            ret.set(state.0 as usize, true);
+    pub fn with_states(self, states: &[State]) -> StateSet {
+        let mut ret = self.empty();
+        for &state in states {
+            ret.add(state);
         }
-        Self(ret)
+        ret
     }
 
-    /// The total number of states, as defined by [Self::scope].
-    #[inline(always)]
-    pub fn len() -> u32 {
-        state_count()
+    /// Superposition of no states.
+    pub fn empty(self) -> StateSet {
+        let mut ret = BitVec::<B>::default();
+        ret.grow(self.len as usize, false);
+        StateSet(ret)
     }
 
     /// Superposition of all states.
-    pub fn all() -> Self {
+    pub fn all(self) -> StateSet {
         let mut ret = BitVec::<B>::default();
-        ret.grow(state_count() as usize, true);
-        Self(ret)
+        ret.grow(self.len as usize, true);
+        StateSet(ret)
+    }
+}
+
+impl StateSet {
+    /// The domain this superposition was created from.
+    #[inline(always)]
+    pub fn domain(&self) -> StateDomain {
+        StateDomain::new(self.0.len() as u32)
     }
 
     /// Total number of possible states, minus 1.
@@ -80,7 +173,17 @@ impl StateSet {
         (self.0.count_ones() as u32).saturating_sub(1)
     }
 
+    /// Are there no possible states left (a contradiction)?
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.0.none()
+    }
+
     /// Is `state` within the superposition?
+    ///
+    /// # Panics
+    ///
+    /// If `state` is outside the domain.
     #[inline(always)]
     pub fn has(&self, state: State) -> bool {
         self.0.get(state.0 as usize).unwrap()
@@ -95,7 +198,21 @@ impl StateSet {
             .any(|(a, b)| a & b != 0)
     }
 
+    /// Number of states in both `self` and `states`.
+    #[inline(always)]
+    pub fn count_common(&self, states: &Self) -> u32 {
+        self.0
+            .blocks()
+            .zip(states.0.blocks())
+            .map(|(a, b)| (a & b).count_ones())
+            .sum()
+    }
+
     /// Remove `state` from the superposition.
+    ///
+    /// # Panics
+    ///
+    /// If `state` is outside the domain.
     #[inline(always)]
     pub fn remove(&mut self, state: State) {
         self.0.set(state.0 as usize, false);
@@ -103,22 +220,31 @@ impl StateSet {
 
     /// Remove all `states` from the superposition.
     pub fn remove_all(&mut self, states: &Self) {
-        for (state, present) in states.0.iter().enumerate() {
-            if present {
-                self.0.set(state, false);
-            }
+        for state in states.iter() {
+            self.remove(state);
         }
     }
 
     /// Add `state` to the superposition.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, if `state` is outside the domain.
     #[inline(always)]
     pub fn add(&mut self, state: State) {
+        debug_assert!(
+            (state.0 as usize) < self.0.len(),
+            "{state:?} outside domain of {} states",
+            self.0.len()
+        );
         self.0.set(state.0 as usize, true);
     }
 
     /// Add all `states` to the superposition.
     pub fn add_all(&mut self, states: &Self) {
-        self.0.or(&states.0);
+        for state in states.iter() {
+            self.add(state);
+        }
     }
 
     pub(crate) fn iter(&self) -> impl Iterator<Item = State> + '_ {
@@ -131,8 +257,7 @@ impl StateSet {
 
     /// Filter states in place.
     pub fn retain(&mut self, mut filter: impl FnMut(State) -> bool) {
-        for s in 0..StateSet::len() {
-            let s = State::nth(s);
+        for s in self.domain().states() {
             if self.has(s) && !filter(s) {
                 self.remove(s);
             }
@@ -140,6 +265,205 @@ impl StateSet {
     }
 }
 
+impl Superposition for StateSet {
+    fn empty(domain: StateDomain) -> Self {
+        domain.empty()
+    }
+
+    fn all(domain: StateDomain) -> Self {
+        domain.all()
+    }
+
+    fn domain(&self) -> StateDomain {
+        StateSet::domain(self)
+    }
+
+    #[inline(always)]
+    fn entropy(&self) -> u32 {
+        StateSet::entropy(self)
+    }
+
+    #[inline(always)]
+    fn is_empty(&self) -> bool {
+        StateSet::is_empty(self)
+    }
+
+    #[inline(always)]
+    fn has(&self, state: State) -> bool {
+        StateSet::has(self, state)
+    }
+
+    #[inline(always)]
+    fn has_any(&self, states: &Self) -> bool {
+        StateSet::has_any(self, states)
+    }
+
+    #[inline(always)]
+    fn count_common(&self, states: &Self) -> u32 {
+        StateSet::count_common(self, states)
+    }
+
+    #[inline(always)]
+    fn remove(&mut self, state: State) {
+        StateSet::remove(self, state)
+    }
+
+    #[inline(always)]
+    fn add(&mut self, state: State) {
+        StateSet::add(self, state)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = State> + '_ {
+        StateSet::iter(self)
+    }
+
+    fn remove_all(&mut self, states: &Self) {
+        StateSet::remove_all(self, states)
+    }
+
+    fn add_all(&mut self, states: &Self) {
+        StateSet::add_all(self, states)
+    }
+
+    fn retain(&mut self, filter: impl FnMut(State) -> bool) {
+        StateSet::retain(self, filter)
+    }
+}
+
+/// A superposition of up to `64 * N` [State]'s, stored inline without
+/// allocating.
+#[derive(PartialEq, Eq, Copy, Clone, Hash, Debug)]
+pub struct InlineStateSet<const N: usize> {
+    blocks: [u64; N],
+    len: u32,
+}
+
+impl<const N: usize> InlineStateSet<N> {
+    /// The maximum number of states.
+    pub const CAPACITY: u32 = 64 * N as u32;
+
+    #[inline(always)]
+    fn position(state: State) -> (usize, u64) {
+        (state.0 as usize / 64, 1 << (state.0 % 64))
+    }
+}
+
+impl<const N: usize> Superposition for InlineStateSet<N> {
+    /// # Panics
+    ///
+    /// If `domain` has more than [InlineStateSet::CAPACITY] states.
+    fn empty(domain: StateDomain) -> Self {
+        assert!(
+            domain.len() <= Self::CAPACITY,
+            "{} states exceed capacity of {}",
+            domain.len(),
+            Self::CAPACITY
+        );
+        Self {
+            blocks: [0; N],
+            len: domain.len(),
+        }
+    }
+
+    fn all(domain: StateDomain) -> Self {
+        let mut ret = Self::empty(domain);
+        for (i, block) in ret.blocks.iter_mut().enumerate() {
+            let bits = domain.len().saturating_sub(i as u32 * 64).min(64);
+            *block = u64::MAX.checked_shr(64 - bits).unwrap_or(0);
+        }
+        ret
+    }
+
+    fn domain(&self) -> StateDomain {
+        StateDomain::new(self.len)
+    }
+
+    #[inline(always)]
+    fn entropy(&self) -> u32 {
+        self.blocks
+            .iter()
+            .map(|b| b.count_ones())
+            .sum::<u32>()
+            .saturating_sub(1)
+    }
+
+    #[inline(always)]
+    fn is_empty(&self) -> bool {
+        self.blocks.iter().all(|&b| b == 0)
+    }
+
+    #[inline(always)]
+    fn has(&self, state: State) -> bool {
+        let (i, mask) = Self::position(state);
+        self.blocks.get(i).is_some_and(|b| b & mask != 0)
+    }
+
+    #[inline(always)]
+    fn has_any(&self, states: &Self) -> bool {
+        self.blocks
+            .iter()
+            .zip(&states.blocks)
+            .any(|(a, b)| a & b != 0)
+    }
+
+    #[inline(always)]
+    fn count_common(&self, states: &Self) -> u32 {
+        self.blocks
+            .iter()
+            .zip(&states.blocks)
+            .map(|(a, b)| (a & b).count_ones())
+            .sum()
+    }
+
+    #[inline(always)]
+    fn remove(&mut self, state: State) {
+        let (i, mask) = Self::position(state);
+        if let Some(b) = self.blocks.get_mut(i) {
+            *b &= !mask;
+        }
+    }
+
+    /// # Panics
+    ///
+    /// If `state` is not within [InlineStateSet::CAPACITY], and in debug
+    /// builds if it is outside the domain.
+    #[inline(always)]
+    fn add(&mut self, state: State) {
+        debug_assert!(
+            state.0 < self.len,
+            "{state:?} outside domain of {} states",
+            self.len
+        );
+        let (i, mask) = Self::position(state);
+        self.blocks[i] |= mask;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = State> + '_ {
+        self.blocks.iter().enumerate().flat_map(|(i, &block)| {
+            let mut block = block;
+            std::iter::from_fn(move || {
+                (block != 0).then(|| {
+                    let bit = block.trailing_zeros();
+                    block &= block - 1;
+                    State::nth(i as u32 * 64 + bit)
+                })
+            })
+        })
+    }
+
+    fn remove_all(&mut self, states: &Self) {
+        for (a, b) in self.blocks.iter_mut().zip(&states.blocks) {
+            *a &= !b;
+        }
+    }
+
+    fn add_all(&mut self, states: &Self) {
+        for (a, b) in self.blocks.iter_mut().zip(&states.blocks) {
+            *a |= b;
+        }
+    }
+}
+
 impl BitOr<Self> for StateSet {
     type Output = Self;
 
@@ -167,14 +491,8 @@ impl BitXor for StateSet {
     }
 }
 
-impl BitOr for State {
-    type Output = StateSet;
-
-    fn bitor(self, rhs: Self) -> Self::Output {
-        StateSet::with_states(&[self, rhs])
-    }
-}
-
+/// Adds a state, such that `domain.empty() | a | b` is a superposition of `a`
+/// and `b`.
 impl BitOr<State> for StateSet {
     type Output = Self;
 
@@ -183,3 +501,77 @@ impl BitOr<State> for StateSet {
         self
     }
 }
+
+/// Superpositions are serialized as their number of states followed by their
+/// states as a bit set in 64-bit blocks, such that [StateSet] and
+/// [InlineStateSet] are interchangeable.
+#[cfg(feature = "serde")]
+fn serialize_blocks<Ser: Serializer>(
+    len: u32,
+    blocks: &[u64],
+    serializer: Ser,
+) -> Result<Ser::Ok, Ser::Error> {
+    (len, &blocks[..len.div_ceil(64) as usize]).serialize(serializer)
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_blocks<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<(StateDomain, Vec<u64>), D::Error> {
+    let (len, blocks) = <(u32, Vec<u64>)>::deserialize(deserializer)?;
+    if blocks.len() != len.div_ceil(64) as usize {
+        return Err(D::Error::custom(format!(
+            "{} blocks for {len} states",
+            blocks.len()
+        )));
+    }
+    if len % 64 != 0 && blocks.last().is_some_and(|&b| b >> (len % 64) != 0) {
+        return Err(D::Error::custom(format!("states beyond {len}")));
+    }
+    Ok((StateDomain::new(len), blocks))
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for StateSet {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serialize_blocks(self.0.len() as u32, self.0.storage(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for StateSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (domain, blocks) = deserialize_blocks(deserializer)?;
+        let mut ret = domain.empty();
+        for state in domain.states() {
+            if blocks[state.0 as usize / 64] & (1 << (state.0 % 64)) != 0 {
+                ret.add(state);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const N: usize> Serialize for InlineStateSet<N> {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serialize_blocks(self.len, &self.blocks, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, const N: usize> Deserialize<'de> for InlineStateSet<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (domain, blocks) = deserialize_blocks(deserializer)?;
+        if domain.len() > Self::CAPACITY {
+            return Err(D::Error::custom(format!(
+                "{} states exceed capacity of {}",
+                domain.len(),
+                Self::CAPACITY
+            )));
+        }
+        let mut ret = Self::empty(domain);
+        ret.blocks[..blocks.len()].copy_from_slice(&blocks);
+        Ok(ret)
+    }
+}
@@ -0,0 +1,282 @@
+use crate::rules::SetCollapseRules;
+use crate::space::Space;
+use crate::state::{State, Superposition};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Support-count (AC-4) propagation.
+///
+/// For every cell, state and direction, counts the states in the neighbor in
+/// that direction that allow the state. A state is removed when any of its
+/// counts reaches zero, and individual removals are propagated.
+pub(crate) struct Supports<C> {
+    coordinates: Vec<C>,
+    indices: HashMap<C, usize>,
+    /// Indexed by `cell * directions + direction`.
+    neighbors: Vec<Option<usize>>,
+    /// Index of the opposite of each direction.
+    opposite: Vec<usize>,
+    /// Indexed by `state * directions + direction`.
+    compatible: Vec<Vec<State>>,
+    /// Indexed by `(cell * states + state) * directions + direction`.
+    counts: Vec<u32>,
+    states: usize,
+    directions: usize,
+    /// Removed states that haven't been propagated yet.
+    bans: Vec<(usize, State)>,
+}
+
+impl<C: Copy + Hash + Eq> Supports<C> {
+    pub(crate) fn new<Sp: Space<S, Coordinate = C>, O, S: Superposition>(
+        space: &Sp,
+        rule: &SetCollapseRules<O, S>,
+    ) -> Self {
+        let directions = Sp::DIRECTIONS.len();
+        let states = rule.state_count() as usize;
+        let mut coordinates = Vec::new();
+        space.visit_coordinates(|coord| coordinates.push(coord));
+        let indices = coordinates
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (*c, i))
+            .collect::<HashMap<_, _>>();
+        let neighbors = coordinates
+            .iter()
+            .flat_map(|&coord| {
+                Sp::DIRECTIONS
+                    .iter()
+                    .map(move |&direction| space.neighbor(coord, direction))
+            })
+            .map(|neighbor| neighbor.map(|n| indices[&n]))
+            .collect::<Vec<_>>();
+        let opposite = Sp::DIRECTIONS
+            .iter()
+            .map(|&direction| Sp::direction_index(-direction))
+            .collect();
+        let compatible = (0..states)
+            .flat_map(|state| {
+                (0..directions).map(move |direction| {
+                    rule.allowed(State::nth(state as u32), direction)
+                        .map(|allowed| allowed.iter().collect())
+                        .unwrap_or_default()
+                })
+            })
+            .collect();
+        let mut counts = vec![0; coordinates.len() * states * directions];
+        for (cell, count) in counts.chunks_mut(states * directions).enumerate() {
+            for direction in 0..directions {
+                let Some(neighbor) = neighbors[cell * directions + direction] else {
+                    continue;
+                };
+                let neighbor = &space[coordinates[neighbor]];
+                for state in 0..states {
+                    count[state * directions + direction] = rule
+                        .allowed(State::nth(state as u32), direction)
+                        .map_or(0, |allowed| allowed.count_common(neighbor));
+                }
+            }
+        }
+        Self {
+            coordinates,
+            indices,
+            neighbors,
+            opposite,
+            compatible,
+            counts,
+            states,
+            directions,
+            bans: Vec::new(),
+        }
+    }
+
+    /// Remove states that are not supported by their neighbors, returning the
+    /// first cell left with no possible states, if any.
+    pub(crate) fn ban_unsupported<Sp: Space<S, Coordinate = C>, S: Superposition>(
+        &mut self,
+        space: &mut Sp,
+        mut on_remove: impl FnMut(C, State),
+    ) -> Result<(), C> {
+        let mut contradiction = None;
+        for (cell, &coord) in self.coordinates.iter().enumerate() {
+            for state in 0..self.states {
+                let s = State::nth(state as u32);
+                if !space[coord].has(s) {
+                    continue;
+                }
+                let unsupported = (0..self.directions).any(|direction| {
+                    self.neighbors[cell * self.directions + direction].is_some()
+                        && self.counts[(cell * self.states + state) * self.directions + direction]
+                            == 0
+                });
+                if unsupported {
+                    space[coord].remove(s);
+                    on_remove(coord, s);
+                    self.bans.push((cell, s));
+                }
+            }
+            if contradiction.is_none() && space[coord].is_empty() {
+                contradiction = Some(coord);
+            }
+        }
+        contradiction.map_or(Ok(()), Err)
+    }
+
+    /// `state` was removed from the cell at `coord`.
+    pub(crate) fn ban(&mut self, coord: C, state: State) {
+        self.bans.push((self.indices[&coord], state));
+    }
+
+    /// `state` was restored to the cell at `coord`.
+    pub(crate) fn restore(&mut self, coord: C, state: State) {
+        let cell = self.indices[&coord];
+        for direction in 0..self.directions {
+            let Some(neighbor) = self.neighbors[cell * self.directions + direction] else {
+                continue;
+            };
+            let opposite = self.opposite[direction];
+            for compatible in &self.compatible[state.0 as usize * self.directions + direction] {
+                self.counts[(neighbor * self.states + compatible.0 as usize) * self.directions
+                    + opposite] += 1;
+            }
+        }
+    }
+
+    /// Propagate bans until there are none left, returning the first cell
+    /// left with no possible states, if any.
+    ///
+    /// Every ban is accounted for even if there is a contradiction, so the
+    /// counts remain consistent with the space.
+    pub(crate) fn propagate<Sp: Space<S, Coordinate = C>, S: Superposition>(
+        &mut self,
+        space: &mut Sp,
+        mut journal: Option<&mut Vec<(C, S)>>,
+        mut on_remove: impl FnMut(C, State),
+    ) -> Result<(), C> {
+        let mut contradiction = None;
+        while let Some((cell, state)) = self.bans.pop() {
+            for direction in 0..self.directions {
+                let Some(neighbor) = self.neighbors[cell * self.directions + direction] else {
+                    continue;
+                };
+                let opposite = self.opposite[direction];
+                for &compatible in &self.compatible[state.0 as usize * self.directions + direction]
+                {
+                    let count = &mut self.counts[(neighbor * self.states + compatible.0 as usize)
+                        * self.directions
+                        + opposite];
+                    *count -= 1;
+                    if *count != 0 || contradiction.is_some() {
+                        continue;
+                    }
+                    let coord = self.coordinates[neighbor];
+                    let cell = &mut space[coord];
+                    if !cell.has(compatible) {
+                        continue;
+                    }
+                    if let Some(journal) = &mut journal {
+                        journal.push((coord, cell.clone()));
+                    }
+                    cell.remove(compatible);
+                    on_remove(coord, compatible);
+                    if cell.is_empty() {
+                        contradiction = Some(coord);
+                    }
+                    self.bans.push((neighbor, compatible));
+                }
+            }
+        }
+        contradiction.map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        counts::tests::random_rule,
+        grid_2d::{Coordinate2d, Grid2d},
+        Backtracking, Cause, Collapser, Contradiction, Propagation, Space, State,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// Step a collapser with each propagation in lockstep, with the same
+    /// seed, such that they observe the same cells as long as propagation
+    /// leaves the same states.
+    ///
+    /// Each rule is also collapsed with some cells initially resolved or
+    /// partially constrained, which may already be a contradiction.
+    #[test]
+    fn propagations_agree() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut constraint_rng = StdRng::seed_from_u64(1);
+        let (mut backtracked, mut contradicted) = (false, 0);
+        for _ in 0..32 {
+            let rule = random_rule(&mut rng);
+            let seed = rng.gen();
+            for constrained in [false, true] {
+                let initial = Grid2d::new(Coordinate2d { x: 8, y: 8 }, |_| {
+                    let mut states = rule.domain().all();
+                    let rng = &mut constraint_rng;
+                    let state = State::nth(rng.gen_range(0..rule.state_count()));
+                    if constrained && rng.gen_bool(0.02) {
+                        states.retain(|s| s == state);
+                    } else if constrained && rng.gen_bool(0.05) {
+                        states.retain(|_| rng.gen_bool(0.5));
+                        states.add(state);
+                    }
+                    states
+                });
+                let mut spaces = [(); 2].map(|_| initial.clone());
+                let mut collapsers = [Propagation::Cellwise, Propagation::SupportCount].map(|p| {
+                    Collapser::new(&spaces[0])
+                        .with_propagation(p)
+                        .with_backtracking(Backtracking::default())
+                });
+                let mut rngs = [(); 2].map(|_| StdRng::seed_from_u64(seed));
+                while !collapsers[0].is_done() {
+                    let resolved = collapsers[0].progress().resolved;
+                    let results =
+                        [0, 1].map(|i| collapsers[i].step(&mut spaces[i], &rule, &mut rngs[i]));
+                    if let [Err(a), Err(b)] = &results {
+                        // Propagations may find different empty cells first.
+                        assert_eq!((a.observed, a.step), (b.observed, b.step));
+                        contradicted += 1;
+                        break;
+                    }
+                    assert_eq!(results[0], results[1]);
+                    assert!(spaces[0] == spaces[1], "step {}", collapsers[0].steps());
+                    assert_eq!(collapsers[0].is_done(), collapsers[1].is_done());
+                    backtracked |= collapsers[0].progress().resolved < resolved;
+                }
+            }
+        }
+        assert!(backtracked);
+        assert!(contradicted > 0);
+    }
+
+    /// Cells emptied by the initial constraints are reported by both
+    /// propagations.
+    #[test]
+    fn initial_contradiction() {
+        let rule = crate::collapse::tests::two_colors();
+        for propagation in [Propagation::Cellwise, Propagation::SupportCount] {
+            let mut space = Grid2d::new(Coordinate2d { x: 2, y: 1 }, |_| {
+                rule.domain().with_states(&[State::nth(0)])
+            });
+            let result = Collapser::new(&space).with_propagation(propagation).run(
+                &mut space,
+                &rule,
+                &mut StdRng::seed_from_u64(0),
+                |_| {},
+            );
+            assert_eq!(
+                result,
+                Err(Contradiction {
+                    cause: Cause::Empty(Coordinate2d { x: 0, y: 0 }),
+                    observed: None,
+                    step: 0,
+                }),
+                "{propagation:?}"
+            );
+        }
+    }
+}
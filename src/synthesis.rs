@@ -0,0 +1,291 @@
+//! Model synthesis, modifying a 3D solution in blocks.
+
+use crate::{
+    collapse,
+    grid_3d::{Axis3d, Coordinate3d, Grid3d},
+    rules::{SetCollapseObserver, SetCollapseRules},
+    Space, Superposition,
+};
+use rand::Rng;
+
+/// Modifies a valid solution in a [`Grid3d`] in overlapping blocks, after
+/// Merrell's model synthesis, which succeeds far more often than collapsing
+/// a large volume at once.
+///
+/// Each block is reset to every state and collapsed on its own, constrained by
+/// the fixed cells around it. Blocks that can't be collapsed are left as they
+/// were, so the space always remains a valid solution.
+///
+/// The observer must be independent of coordinates (see
+/// [SetCollapseObserver::is_coordinate_independent]).
+#[derive(Debug, Clone)]
+pub struct ModelSynthesis {
+    block_size: Coordinate3d,
+    stride: Coordinate3d,
+    passes: u32,
+    max_attempts: u32,
+}
+
+/// Outcome of [ModelSynthesis::run].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SynthesisReport {
+    /// Number of blocks collapsed, including failed ones.
+    pub blocks: u32,
+    /// Number of blocks that couldn't be collapsed, and were left unchanged.
+    pub failed: u32,
+}
+
+/// Cells of a block and its border along one axis.
+struct AxisCells {
+    /// Coordinates in the space, indexed by coordinate in the block's grid.
+    cells: Vec<u32>,
+    /// Coordinates in the block's grid that are within the block.
+    block: std::ops::Range<u32>,
+    /// Whether the block spans a whole periodic axis.
+    periodic: bool,
+}
+
+impl ModelSynthesis {
+    /// Modify blocks of `block_size` cells, overlapping by half.
+    ///
+    /// # Panics
+    ///
+    /// If any dimension of `block_size` is zero.
+    pub fn new(block_size: Coordinate3d) -> Self {
+        assert!(
+            block_size.x > 0 && block_size.y > 0 && block_size.z > 0,
+            "block size must be positive"
+        );
+        Self {
+            block_size,
+            stride: Coordinate3d {
+                x: block_size.x.div_ceil(2),
+                y: block_size.y.div_ceil(2),
+                z: block_size.z.div_ceil(2),
+            },
+            passes: 1,
+            max_attempts: 1,
+        }
+    }
+
+    /// Offset between consecutive blocks along each axis. Defaults to half of
+    /// the block size, rounded up.
+    ///
+    /// # Panics
+    ///
+    /// If any dimension of `stride` is zero.
+    pub fn with_stride(mut self, stride: Coordinate3d) -> Self {
+        assert!(
+            stride.x > 0 && stride.y > 0 && stride.z > 0,
+            "stride must be positive"
+        );
+        self.stride = stride;
+        self
+    }
+
+    /// Number of times to sweep every block of the space. Defaults to 1.
+    pub fn with_passes(mut self, passes: u32) -> Self {
+        self.passes = passes;
+        self
+    }
+
+    /// Try up to `max_attempts` times to collapse each block before leaving
+    /// it unchanged. Defaults to 1.
+    ///
+    /// # Panics
+    ///
+    /// If `max_attempts` is zero.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        assert!(max_attempts > 0, "must make at least one attempt");
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Modify `space`, which must already be a valid, fully collapsed
+    /// solution (e.g. all ground), by sweeping blocks along x, then y, then
+    /// z.
+    ///
+    /// # Panics
+    ///
+    /// If the observer of `rule` depends on coordinates.
+    pub fn run<O: SetCollapseObserver<Coordinate3d>, S: Superposition>(
+        &self,
+        space: &mut Grid3d<S>,
+        rule: &SetCollapseRules<O, S>,
+        rng: &mut impl Rng,
+    ) -> SynthesisReport {
+        assert!(
+            rule.observer().is_coordinate_independent(),
+            "observer must not depend on coordinates"
+        );
+        let dimensions = space.dimensions();
+        let origins = [Axis3d::X, Axis3d::Y, Axis3d::Z].map(|axis| {
+            block_origins(
+                self.block_size[axis],
+                self.stride[axis],
+                dimensions[axis],
+                space.is_periodic(axis),
+            )
+        });
+        let mut report = SynthesisReport {
+            blocks: 0,
+            failed: 0,
+        };
+        for _ in 0..self.passes {
+            for &z in &origins[2] {
+                for &y in &origins[1] {
+                    for &x in &origins[0] {
+                        report.blocks += 1;
+                        if !self.modify_block(space, rule, Coordinate3d { x, y, z }, rng) {
+                            report.failed += 1;
+                        }
+                    }
+                }
+            }
+        }
+        report
+    }
+
+    /// Reset and collapse the block at `origin`, returning whether it
+    /// succeeded.
+    fn modify_block<O: SetCollapseObserver<Coordinate3d>, S: Superposition>(
+        &self,
+        space: &mut Grid3d<S>,
+        rule: &SetCollapseRules<O, S>,
+        origin: Coordinate3d,
+        rng: &mut impl Rng,
+    ) -> bool {
+        let dimensions = space.dimensions();
+        let [x, y, z] = [Axis3d::X, Axis3d::Y, Axis3d::Z].map(|axis| {
+            axis_cells(
+                origin[axis],
+                self.block_size[axis],
+                dimensions[axis],
+                space.is_periodic(axis),
+            )
+        });
+        let in_block = |c: Coordinate3d| {
+            x.block.contains(&c.x) && y.block.contains(&c.y) && z.block.contains(&c.z)
+        };
+        let to_space = |c: Coordinate3d| Coordinate3d {
+            x: x.cells[c.x as usize],
+            y: y.cells[c.y as usize],
+            z: z.cells[c.z as usize],
+        };
+        let initial = Grid3d::new(
+            Coordinate3d {
+                x: x.cells.len() as u32,
+                y: y.cells.len() as u32,
+                z: z.cells.len() as u32,
+            },
+            |c| {
+                if in_block(c) {
+                    S::all(rule.domain())
+                } else {
+                    space[to_space(c)].clone()
+                }
+            },
+        )
+        .with_periodic(Axis3d::X, x.periodic)
+        .with_periodic(Axis3d::Y, y.periodic)
+        .with_periodic(Axis3d::Z, z.periodic);
+
+        for _ in 0..self.max_attempts {
+            let mut block = initial.clone();
+            if collapse(&mut block, rule, rng, |_| {}).is_ok() {
+                block.visit_coordinates(|c| {
+                    if in_block(c) {
+                        space[to_space(c)] = block[c].clone();
+                    }
+                });
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Origins of blocks of `size` every `stride` along an axis of `len`.
+fn block_origins(size: u32, stride: u32, len: u32, periodic: bool) -> Vec<u32> {
+    if size >= len {
+        return vec![0];
+    }
+    let last = if periodic { len - 1 } else { len - size };
+    let mut origins = (0..=last).step_by(stride as usize).collect::<Vec<_>>();
+    if !periodic && origins.last() != Some(&last) {
+        origins.push(last);
+    }
+    origins
+}
+
+/// Cells of the block at `origin` of `size` along an axis of `len`, and the
+/// cells bordering it, if any.
+fn axis_cells(origin: u32, size: u32, len: u32, periodic: bool) -> AxisCells {
+    if size >= len {
+        return AxisCells {
+            cells: (0..len).collect(),
+            block: 0..len,
+            periodic,
+        };
+    }
+    if periodic {
+        // Wraps around, possibly bordering the same cell on both sides.
+        AxisCells {
+            cells: (0..size + 2)
+                .map(|i| (origin + len - 1 + i) % len)
+                .collect(),
+            block: 1..size + 1,
+            periodic: false,
+        }
+    } else {
+        let start = origin.saturating_sub(1);
+        let end = (origin + size + 1).min(len);
+        AxisCells {
+            cells: (start..end).collect(),
+            block: origin - start..origin - start + size,
+            periodic: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ModelSynthesis;
+    use crate::{
+        grid_3d::{Axis3d, Coordinate3d, Grid3d},
+        rules::tests::coordinate_dependent,
+        sockets::tests::pipes,
+        verify::tests::assert_valid,
+        Space, State, StateSet,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn synthesis() {
+        let rule = pipes::<Grid3d<StateSet>, _>();
+        let no_pipes = rule.domain().with_states(&[State::nth(0)]);
+        let mut space = Grid3d::new(Coordinate3d { x: 8, y: 6, z: 4 }, |_| no_pipes.clone())
+            .with_periodic(Axis3d::X, true);
+        let report = ModelSynthesis::new(Coordinate3d { x: 4, y: 4, z: 4 })
+            .with_passes(2)
+            .with_max_attempts(4)
+            .run(&mut space, &rule, &mut StdRng::seed_from_u64(0));
+        assert!(report.blocks > 0);
+        assert_valid(&space, &rule);
+        let mut changed = false;
+        space.visit_coordinates(|c| changed |= space[c] != no_pipes);
+        assert!(changed, "no blocks changed");
+    }
+
+    #[test]
+    #[should_panic(expected = "observer must not depend on coordinates")]
+    fn rejects_coordinate_dependent_observers() {
+        let rule = coordinate_dependent::<Grid3d<StateSet>>();
+        let mut space = Grid3d::new(Coordinate3d { x: 2, y: 2, z: 2 }, |_| rule.domain().all());
+        ModelSynthesis::new(Coordinate3d { x: 1, y: 1, z: 1 }).run(
+            &mut space,
+            &rule,
+            &mut StdRng::seed_from_u64(0),
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Simple tiled model.
+
+use crate::{
+    grid_2d::{Coordinate2d, Direction2d, Grid2d},
+    rules::{SetCollapseRules, SetCollapseRulesBuilder},
+    state::{State, StateDomain, Superposition},
+};
+
+/// Symmetry of a tile, named after a letter with the same symmetry (assuming
+/// +y is drawn downwards, as in images).
+///
+/// Base tiles must actually have the symmetry they are added with, or else
+/// the generated rules won't match the rendered tiles.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Symmetry {
+    /// Unchanged by every rotation and reflection. 1 orientation.
+    X,
+    /// Unchanged by reflection that swaps the left and right edges. 4
+    /// orientations.
+    T,
+    /// Unchanged by 180 degree rotation, and reflection that swaps the left
+    /// and right edges. 2 orientations.
+    I,
+    /// Unchanged by reflection that swaps the right and down edges (and the
+    /// left and up edges). 4 orientations.
+    L,
+    /// Unchanged by 180 degree rotation, and reflection that swaps the right
+    /// and up edges (and the left and down edges). 2 orientations.
+    Backslash,
+    /// Unchanged only by the identity. 8 orientations.
+    F,
+}
+
+/// Rotation and reflection of a tile.
+///
+/// The tile is first flipped, if [`Orientation::flipped`], across the y axis
+/// (swapping its left and right edges), then rotated 90 degrees
+/// counter-clockwise [`Orientation::rotation`] times (each time turning its
+/// right edge into its up edge).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Orientation {
+    rotation: u8,
+    flipped: bool,
+}
+
+/// Base tile in a [`TiledModel`], which is also its index in the order tiles
+/// were added.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TileId(pub u32);
+
+impl Symmetry {
+    /// Whether a tile with this symmetry is unchanged by `orientation`.
+    fn is_unchanged_by(self, orientation: Orientation) -> bool {
+        let Orientation { rotation, flipped } = orientation;
+        match self {
+            Self::X => true,
+            Self::T => rotation == 0,
+            Self::I => rotation % 2 == 0,
+            Self::L => rotation == flipped as u8,
+            Self::Backslash => rotation % 2 == flipped as u8,
+            Self::F => rotation == 0 && !flipped,
+        }
+    }
+
+    /// Number of distinct orientations of a tile with this symmetry.
+    pub fn cardinality(self) -> u32 {
+        let unchanged = Orientation::ALL
+            .iter()
+            .filter(|&&o| self.is_unchanged_by(o))
+            .count();
+        (Orientation::ALL.len() / unchanged) as u32
+    }
+}
+
+impl Orientation {
+    /// Every orientation, unflipped ones first, in order of rotation.
+    pub const ALL: [Self; 8] = {
+        let mut all = [Self {
+            rotation: 0,
+            flipped: false,
+        }; 8];
+        let mut i = 0;
+        while i < 8 {
+            all[i] = Self {
+                rotation: (i % 4) as u8,
+                flipped: i >= 4,
+            };
+            i += 1;
+        }
+        all
+    };
+
+    /// Flip (if `flipped`), then rotate 90 degrees counter-clockwise
+    /// `rotation` times.
+    pub fn new(rotation: u32, flipped: bool) -> Self {
+        Self {
+            rotation: (rotation % 4) as u8,
+            flipped,
+        }
+    }
+
+    /// Number of 90 degree counter-clockwise rotations, in `0..4`.
+    pub fn rotation(self) -> u32 {
+        self.rotation as u32
+    }
+
+    /// Whether the tile is flipped before being rotated.
+    pub fn flipped(self) -> bool {
+        self.flipped
+    }
+
+    /// Apply `self` after `other`.
+    pub fn then(self, other: Self) -> Self {
+        let rotation = if self.flipped {
+            self.rotation + 4 - other.rotation
+        } else {
+            self.rotation + other.rotation
+        };
+        Self {
+            rotation: rotation % 4,
+            flipped: self.flipped != other.flipped,
+        }
+    }
+
+    /// The orientation that undoes `self`.
+    pub fn inverse(self) -> Self {
+        if self.flipped {
+            self
+        } else {
+            Self {
+                rotation: (4 - self.rotation) % 4,
+                flipped: false,
+            }
+        }
+    }
+
+    /// The edge of the oriented tile that `direction` of the base tile ends
+    /// up at.
+    pub fn direction(self, direction: Direction2d) -> Direction2d {
+        const CCW: [Direction2d; 4] = [
+            Direction2d::Right,
+            Direction2d::Up,
+            Direction2d::Left,
+            Direction2d::Down,
+        ];
+        let direction = if self.flipped {
+            match direction {
+                Direction2d::Right => Direction2d::Left,
+                Direction2d::Left => Direction2d::Right,
+                other => other,
+            }
+        } else {
+            direction
+        };
+        let index = CCW.iter().position(|&d| d == direction).unwrap();
+        CCW[(index + self.rotation as usize) % 4]
+    }
+
+    /// Where the pixel (or other cell) at `coordinate` of a base tile of
+    /// `size` by `size` ends up in the oriented tile, for rendering.
+    pub fn transform(self, coordinate: Coordinate2d, size: u32) -> Coordinate2d {
+        let Coordinate2d { mut x, mut y } = coordinate;
+        if self.flipped {
+            x = size - 1 - x;
+        }
+        for _ in 0..self.rotation {
+            (x, y) = (size - 1 - y, x);
+        }
+        Coordinate2d { x, y }
+    }
+}
+
+/// Builder of adjacency rules for a [`Grid2d`], where each [`State`] is a
+/// distinct orientation of a base tile.
+///
+/// Each base tile is added once, with its [`Symmetry`], and adjacencies are
+/// declared for any one orientation of the tiles involved. Every rotation and
+/// reflection of each adjacency is implied.
+#[derive(Clone, Debug, Default)]
+pub struct TiledModel {
+    /// Indexed by [`TileId`].
+    tiles: Vec<TileInfo>,
+    /// Indexed by [`State`].
+    variants: Vec<(TileId, Orientation)>,
+    /// States, and the direction from the first to the second.
+    adjacencies: Vec<(State, Direction2d, State)>,
+}
+
+#[derive(Clone, Debug)]
+struct TileInfo {
+    symmetry: Symmetry,
+    /// State of each orientation, indexed by [`Orientation::ALL`].
+    states: [State; 8],
+}
+
+impl TiledModel {
+    /// Create a model with no tiles.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a base tile, with one [`State`] per distinct orientation.
+    pub fn add_tile(&mut self, symmetry: Symmetry) -> TileId {
+        let tile = TileId(self.tiles.len() as u32);
+        let mut states = [State::nth(0); 8];
+        for (i, &orientation) in Orientation::ALL.iter().enumerate() {
+            // The first equivalent orientation is canonical.
+            let existing = Orientation::ALL[..i]
+                .iter()
+                .position(|&earlier| symmetry.is_unchanged_by(orientation.inverse().then(earlier)));
+            states[i] = if let Some(existing) = existing {
+                states[existing]
+            } else {
+                self.variants.push((tile, orientation));
+                State::nth(self.variants.len() as u32 - 1)
+            };
+        }
+        self.tiles.push(TileInfo { symmetry, states });
+        tile
+    }
+
+    /// Allow `neighbor` in `direction` of `tile`, where both are specified as
+    /// a base tile in some orientation.
+    ///
+    /// # Panics
+    ///
+    /// If either tile hasn't been added.
+    pub fn allow(
+        &mut self,
+        tile: (TileId, Orientation),
+        direction: Direction2d,
+        neighbor: (TileId, Orientation),
+    ) {
+        for orientation in Orientation::ALL {
+            self.adjacencies.push((
+                self.state(tile.0, orientation.then(tile.1)),
+                orientation.direction(direction),
+                self.state(neighbor.0, orientation.then(neighbor.1)),
+            ));
+        }
+    }
+
+    /// The symmetry `tile` was added with.
+    pub fn symmetry(&self, tile: TileId) -> Symmetry {
+        self.tiles[tile.0 as usize].symmetry
+    }
+
+    /// State of `tile` in `orientation`, or an equivalent orientation
+    /// according to its [`Symmetry`].
+    pub fn state(&self, tile: TileId, orientation: Orientation) -> State {
+        let index = Orientation::ALL
+            .iter()
+            .position(|&o| o == orientation)
+            .unwrap();
+        self.tiles[tile.0 as usize].states[index]
+    }
+
+    /// Base tile and orientation of `state`, for rendering.
+    pub fn variant(&self, state: State) -> (TileId, Orientation) {
+        self.variants[state.0 as usize]
+    }
+
+    /// The domain of all states, for creating weighted observers.
+    pub fn domain(&self) -> StateDomain {
+        StateDomain::new(self.variants.len() as u32)
+    }
+
+    /// Build [`SetCollapseRules`] from the declared adjacencies.
+    pub fn rules<O: Clone, S: Superposition>(&self, observer: O) -> SetCollapseRules<O, S> {
+        let domain = self.domain();
+        let mut builder = SetCollapseRulesBuilder::<Grid2d<S>, O, S>::new(domain, observer);
+        for &(state, direction, neighbor) in &self.adjacencies {
+            builder = builder.allow(state, &[(direction, domain.with_states(&[neighbor]))]);
+        }
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Orientation, Symmetry, TiledModel};
+    use crate::{
+        collapse_backtracking,
+        grid_2d::{Coordinate2d, Direction2d, Grid2d},
+        rules::UniformSetCollapseObserver,
+        verify::tests::assert_valid,
+        Backtracking, Space, StateSet,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn collapse_tiled() {
+        // Loops, drawn with lines, corners and crossings on empty tiles.
+        let mut model = TiledModel::new();
+        let empty = model.add_tile(Symmetry::X);
+        let line = model.add_tile(Symmetry::I);
+        let corner = model.add_tile(Symmetry::L);
+        let cross = model.add_tile(Symmetry::X);
+        let (o, turn) = (Orientation::default(), Orientation::new(1, false));
+        // Lines run along x, and corners connect the right and up edges.
+        for (left, right) in [
+            ((empty, o), (empty, o)),
+            ((empty, o), (line, turn)),
+            ((empty, o), (corner, o)),
+            ((line, o), (line, o)),
+            ((line, o), (cross, o)),
+            ((line, o), (corner, Orientation::new(2, false))),
+            ((corner, o), (line, o)),
+            ((corner, o), (cross, o)),
+            ((corner, Orientation::new(1, false)), (empty, o)),
+            ((cross, o), (cross, o)),
+        ] {
+            model.allow(left, Direction2d::Right, right);
+        }
+        let rule = model.rules::<_, StateSet>(UniformSetCollapseObserver);
+        assert_eq!(rule.domain().len(), 1 + 2 + 4 + 1);
+        for seed in 0..4 {
+            let mut space = Grid2d::new(Coordinate2d { x: 8, y: 8 }, |_| rule.domain().all());
+            let mut rng = StdRng::seed_from_u64(seed);
+            collapse_backtracking(&mut space, &rule, &mut rng, Backtracking::default(), |_| {})
+                .unwrap();
+            assert_valid(&space, &rule);
+        }
+    }
+}
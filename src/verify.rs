@@ -0,0 +1,165 @@
+use crate::rules::SetCollapseRules;
+use crate::space::Space;
+use crate::state::Superposition;
+use std::fmt::{self, Debug, Display, Formatter};
+
+/// Problems with a space, found by [verify].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification<C, D> {
+    /// Cells with no possible states.
+    pub empty: Vec<C>,
+    /// Cells with more than one possible state.
+    pub unresolved: Vec<C>,
+    /// Neighboring cells that aren't allowed next to each other.
+    pub violations: Vec<Violation<C, D>>,
+}
+
+/// A pair of neighboring cells, where some state of one isn't allowed next to
+/// any state of the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation<C, D> {
+    /// The first cell.
+    pub coordinate: C,
+    /// Direction from [Self::coordinate] to [Self::neighbor].
+    pub direction: D,
+    /// The second cell.
+    pub neighbor: C,
+}
+
+impl<C, D> Verification<C, D> {
+    /// Whether the space is fully collapsed and satisfies the rules.
+    pub fn is_valid(&self) -> bool {
+        self.empty.is_empty() && self.unresolved.is_empty() && self.violations.is_empty()
+    }
+}
+
+impl<C: Debug, D: Debug> Display for Verification<C, D> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_valid() {
+            return write!(f, "valid");
+        }
+        let mut problems = Vec::new();
+        for coordinate in &self.empty {
+            problems.push(format!("empty cell at {coordinate:?}"));
+        }
+        for coordinate in &self.unresolved {
+            problems.push(format!("unresolved cell at {coordinate:?}"));
+        }
+        for violation in &self.violations {
+            problems.push(format!(
+                "{:?} not allowed at {:?} of {:?}",
+                violation.neighbor, violation.direction, violation.coordinate
+            ));
+        }
+        write!(f, "{}", problems.join(", "))
+    }
+}
+
+/// Check that every cell of `space` has exactly one state, and that every
+/// pair of neighboring cells is allowed by `rules`.
+///
+/// Each violating pair is reported once. Empty cells aren't checked against
+/// their neighbors, and a pair involving an unresolved cell is a violation if
+/// any of its states isn't allowed next to any state of the other cell.
+pub fn verify<Sp: Space<S>, O, S: Superposition>(
+    space: &Sp,
+    rules: &SetCollapseRules<O, S>,
+) -> Verification<Sp::Coordinate, Sp::Direction> {
+    let mut verification = Verification {
+        empty: Vec::new(),
+        unresolved: Vec::new(),
+        violations: Vec::new(),
+    };
+    space.visit_coordinates(|coordinate| {
+        let cell = &space[coordinate];
+        if cell.is_empty() {
+            verification.empty.push(coordinate);
+        } else if cell.entropy() > 0 {
+            verification.unresolved.push(coordinate);
+        }
+        for (i, &direction) in Sp::DIRECTIONS.iter().enumerate() {
+            let Some(neighbor) = space.neighbor(coordinate, direction) else {
+                continue;
+            };
+            // Visit each pair from one side only, checking both.
+            if neighbor < coordinate || cell.is_empty() || space[neighbor].is_empty() {
+                continue;
+            }
+            let opposite = Sp::direction_index(-direction);
+            if !supported(rules, cell, i, &space[neighbor])
+                || !supported(rules, &space[neighbor], opposite, cell)
+            {
+                verification.violations.push(Violation {
+                    coordinate,
+                    direction,
+                    neighbor,
+                });
+            }
+        }
+    });
+    verification
+}
+
+/// Whether every state of `cell` allows some state of `neighbor` in the
+/// direction with index `direction`.
+fn supported<O, S: Superposition>(
+    rules: &SetCollapseRules<O, S>,
+    cell: &S,
+    direction: usize,
+    neighbor: &S,
+) -> bool {
+    cell.iter().all(|state| {
+        rules
+            .allowed(state, direction)
+            .is_some_and(|allowed| allowed.has_any(neighbor))
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{verify, Violation};
+    use crate::{
+        grid_2d::{Coordinate2d, Direction2d, Grid2d},
+        rules::SetCollapseRules,
+        sockets::tests::pipes,
+        Space, State, StateSet,
+    };
+    use std::fmt::Debug;
+
+    pub(crate) fn assert_valid<Sp: Space<StateSet>, O>(space: &Sp, rule: &SetCollapseRules<O>)
+    where
+        Sp::Coordinate: Debug,
+    {
+        let verification = verify(space, rule);
+        assert!(verification.is_valid(), "{verification}");
+    }
+
+    #[test]
+    fn verify_reports_problems() {
+        let rule = pipes::<Grid2d<StateSet>, _>();
+        let domain = rule.domain();
+        // A pipe on the right side, next to a cell with no pipe on its left.
+        let mut space = Grid2d::new(Coordinate2d { x: 3, y: 1 }, |_| {
+            domain.with_states(&[State::nth(0)])
+        });
+        space[Coordinate2d { x: 0, y: 0 }] = domain.with_states(&[State::nth(1)]);
+        space[Coordinate2d { x: 2, y: 0 }] = domain.empty();
+        let verification = verify(&space, &rule);
+        assert_eq!(verification.empty, [Coordinate2d { x: 2, y: 0 }]);
+        assert!(verification.unresolved.is_empty());
+        assert_eq!(
+            verification.violations,
+            [Violation {
+                coordinate: Coordinate2d { x: 0, y: 0 },
+                direction: Direction2d::Right,
+                neighbor: Coordinate2d { x: 1, y: 0 },
+            }]
+        );
+
+        space[Coordinate2d { x: 2, y: 0 }] = domain.all();
+        assert_eq!(
+            verify(&space, &rule).unresolved,
+            [Coordinate2d { x: 2, y: 0 }]
+        );
+    }
+}
@@ -0,0 +1,926 @@
+//! Loading models in the XML format of the original
+//! [WaveFunctionCollapse](https://github.com/mxgmn/WaveFunctionCollapse).
+//!
+//! Requires the `xml` feature.
+
+use crate::{
+    grid_2d::{Axis2d, Coordinate2d, Direction2d, Grid2d},
+    overlapping::{codify_variants, ExtractedPatterns, Tile},
+    rules::{SetCollapseRules, WeightedSetCollapseObserver},
+    state::{State, StateSet, Superposition},
+    tiled::{Orientation, Symmetry, TileId, TiledModel},
+    Space,
+};
+use roxmltree::{Document, Node};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    hash::Hash,
+    str::FromStr,
+};
+
+/// A model couldn't be loaded.
+#[derive(Debug)]
+pub enum XmlError {
+    /// The document isn't well-formed XML.
+    Parse(roxmltree::Error),
+    /// An element or attribute is missing or has an invalid value.
+    Invalid(String),
+}
+
+impl Display for XmlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "malformed XML: {e}"),
+            Self::Invalid(message) => write!(f, "invalid model: {message}"),
+        }
+    }
+}
+
+impl Error for XmlError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Parse(e) => Some(e),
+            Self::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<roxmltree::Error> for XmlError {
+    fn from(e: roxmltree::Error) -> Self {
+        Self::Parse(e)
+    }
+}
+
+/// Simple tiled model, loaded from a tileset (`<set>`) with `<tiles>`,
+/// `<neighbors>` and optionally `<subsets>`.
+#[derive(Clone, Debug)]
+pub struct Tileset {
+    model: TiledModel,
+    /// Indexed by [`TileId`].
+    names: Vec<String>,
+    /// Indexed by [`TileId`].
+    weights: Vec<f64>,
+    size: Option<u32>,
+    unique: bool,
+}
+
+impl Tileset {
+    /// Parse a tileset, only including the tiles in `subset` if specified.
+    pub fn parse(xml: &str, subset: Option<&str>) -> Result<Self, XmlError> {
+        let document = Document::parse(xml)?;
+        let set = document.root_element();
+        expect_name(set, "set")?;
+
+        let included = if let Some(subset) = subset {
+            let subset = children(set, "subsets")
+                .flat_map(|subsets| children(subsets, "subset"))
+                .find(|s| s.attribute("name") == Some(subset))
+                .ok_or_else(|| XmlError::Invalid(format!("no subset named {subset:?}")))?;
+            Some(
+                children(subset, "tile")
+                    .map(|tile| required::<String>(tile, "name"))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+        } else {
+            None
+        };
+
+        let mut ret = Self {
+            model: TiledModel::new(),
+            names: Vec::new(),
+            weights: Vec::new(),
+            size: optional(set, "size")?,
+            unique: optional_bool(set, "unique")?.unwrap_or(false),
+        };
+
+        // Neighbors of excluded tiles are skipped, but any other tile name is
+        // an error.
+        let mut excluded = Vec::new();
+        for tile in children(set, "tiles").flat_map(|tiles| children(tiles, "tile")) {
+            let name = required::<String>(tile, "name")?;
+            if ret.tile(&name).is_some() || excluded.contains(&name) {
+                return Err(XmlError::Invalid(format!("duplicate tile {name:?}")));
+            }
+            if included.as_ref().is_some_and(|i| !i.contains(&name)) {
+                excluded.push(name);
+                continue;
+            }
+            let symmetry = match tile.attribute("symmetry").unwrap_or("X") {
+                "X" => Symmetry::X,
+                "T" => Symmetry::T,
+                "I" => Symmetry::I,
+                "L" => Symmetry::L,
+                "\\" => Symmetry::Backslash,
+                "F" => Symmetry::F,
+                other => {
+                    return Err(XmlError::Invalid(format!(
+                        "unknown symmetry {other:?} of tile {name:?}"
+                    )))
+                }
+            };
+            let weight: f64 = optional(tile, "weight")?.unwrap_or(1.0);
+            if !(weight.is_finite() && weight >= 0.0) {
+                return Err(XmlError::Invalid(format!(
+                    "invalid weight {weight} of tile {name:?}"
+                )));
+            }
+            ret.model.add_tile(symmetry);
+            ret.names.push(name);
+            ret.weights.push(weight);
+        }
+
+        if let Some(name) = included.iter().flatten().find(|n| ret.tile(n).is_none()) {
+            return Err(XmlError::Invalid(format!(
+                "unknown tile {name:?} in subset"
+            )));
+        }
+
+        for neighbor in children(set, "neighbors").flat_map(|n| children(n, "neighbor")) {
+            let (Some(left), Some(right)) = (
+                ret.parse_variant(required(neighbor, "left")?, &excluded)?,
+                ret.parse_variant(required(neighbor, "right")?, &excluded)?,
+            ) else {
+                // Excluded by the subset.
+                continue;
+            };
+            ret.model.allow(left, Direction2d::Right, right);
+        }
+
+        Ok(ret)
+    }
+
+    /// Parse a tile name, optionally followed by an orientation index,
+    /// returning `None` if the tile is one of `excluded`.
+    fn parse_variant(
+        &self,
+        variant: String,
+        excluded: &[String],
+    ) -> Result<Option<(TileId, Orientation)>, XmlError> {
+        let (name, index) = match variant.rsplit_once(' ') {
+            Some((name, index)) => (
+                name,
+                index.parse::<u32>().map_err(|_| {
+                    XmlError::Invalid(format!("invalid orientation in {variant:?}"))
+                })?,
+            ),
+            None => (variant.as_str(), 0),
+        };
+        let Some(tile) = self.tile(name) else {
+            if excluded.iter().any(|n| n == name) {
+                return Ok(None);
+            }
+            return Err(XmlError::Invalid(format!(
+                "unknown tile {name:?} in {variant:?}"
+            )));
+        };
+        if index >= self.model.symmetry(tile).cardinality() {
+            return Err(XmlError::Invalid(format!(
+                "orientation out of range in {variant:?}"
+            )));
+        }
+        Ok(Some((tile, wfc_orientation(index))))
+    }
+
+    /// The tiled model, for mapping between [`State`]s and tile orientations.
+    pub fn model(&self) -> &TiledModel {
+        &self.model
+    }
+
+    /// The tile named `name`, if it was loaded.
+    pub fn tile(&self, name: &str) -> Option<TileId> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .map(|i| TileId(i as u32))
+    }
+
+    /// The name of `tile`.
+    pub fn name(&self, tile: TileId) -> &str {
+        &self.names[tile.0 as usize]
+    }
+
+    /// The weight of `tile`, which applies to each of its orientations.
+    pub fn weight(&self, tile: TileId) -> f64 {
+        self.weights[tile.0 as usize]
+    }
+
+    /// Size of the tiles, in pixels, if specified.
+    pub fn size(&self) -> Option<u32> {
+        self.size
+    }
+
+    /// The image to draw for `state`, as a file name without extension and
+    /// the orientation to apply to it (see [`Orientation::transform`]).
+    ///
+    /// If the tileset is `unique`, each orientation has its own image, named
+    /// after the tile and its orientation index, and no orientation needs to
+    /// be applied. Otherwise, there is one image per tile, named after it.
+    pub fn image(&self, state: State) -> (String, Orientation) {
+        let (tile, orientation) = self.model.variant(state);
+        let name = self.name(tile);
+        if self.unique {
+            let index = (0..self.model.symmetry(tile).cardinality())
+                .find(|&i| self.model.state(tile, wfc_orientation(i)) == state)
+                .unwrap();
+            (format!("{name} {index}"), Orientation::default())
+        } else {
+            (name.to_owned(), orientation)
+        }
+    }
+
+    /// An observer that weights each state by the weight of its tile.
+    pub fn observer(&self) -> WeightedSetCollapseObserver<Coordinate2d> {
+        let mut observer = WeightedSetCollapseObserver::new(self.model.domain());
+        for i in 0..self.model.domain().len() {
+            let state = State::nth(i);
+            observer.set_weight(state, self.weight(self.model.variant(state).0));
+        }
+        observer
+    }
+
+    /// Build [`SetCollapseRules`] for a [`Grid2d`], using [`Self::observer`].
+    pub fn rules<S: Superposition>(
+        &self,
+    ) -> SetCollapseRules<WeightedSetCollapseObserver<Coordinate2d>, S> {
+        self.model.rules(self.observer())
+    }
+}
+
+/// Entry of a `samples.xml`-style list of models to generate.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sample {
+    /// `<overlapping>`
+    Overlapping(OverlappingSample),
+    /// `<simpletiled>`
+    SimpleTiled(SimpleTiledSample),
+}
+
+/// Overlapping model to generate from a sample image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverlappingSample {
+    /// Name of the sample image.
+    pub name: String,
+    /// Size of the patterns (`N`).
+    pub n: u32,
+    /// Size of the output.
+    pub dimensions: Coordinate2d,
+    /// Whether the sample image wraps around (`periodicInput`).
+    pub periodic_input: bool,
+    /// Whether the output wraps around.
+    pub periodic: bool,
+    /// Number of rotations and reflections of each pattern to include, from 1
+    /// to 8. The original includes the first `symmetry` of 8 variants,
+    /// alternately reflecting across the X axis and rotating.
+    pub symmetry: u32,
+    /// Whether the bottom row of the sample should be the bottom row of the
+    /// output (see [`OverlappingModel::constrain`]).
+    pub ground: bool,
+}
+
+/// Simple tiled model to generate from a tileset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimpleTiledSample {
+    /// Name of the tileset.
+    pub name: String,
+    /// Subset of the tileset to use.
+    pub subset: Option<String>,
+    /// Size of the output, in tiles.
+    pub dimensions: Coordinate2d,
+    /// Whether the output wraps around.
+    pub periodic: bool,
+}
+
+impl Sample {
+    /// Parse every `<overlapping>` and `<simpletiled>` entry of a `<samples>`
+    /// list, ignoring other elements.
+    pub fn parse_all(xml: &str) -> Result<Vec<Self>, XmlError> {
+        let document = Document::parse(xml)?;
+        let samples = document.root_element();
+        expect_name(samples, "samples")?;
+        let mut ret = Vec::new();
+        for sample in samples.children().filter(Node::is_element) {
+            match sample.tag_name().name() {
+                "overlapping" => {
+                    ret.push(Self::Overlapping(OverlappingSample {
+                        name: required(sample, "name")?,
+                        n: optional(sample, "N")?.unwrap_or(3),
+                        dimensions: dimensions(sample, 48)?,
+                        periodic_input: optional_bool(sample, "periodicInput")?.unwrap_or(true),
+                        periodic: optional_bool(sample, "periodic")?.unwrap_or(false),
+                        symmetry: optional(sample, "symmetry")?.unwrap_or(8),
+                        ground: optional_bool(sample, "ground")?.unwrap_or(false),
+                    }));
+                }
+                "simpletiled" => {
+                    ret.push(Self::SimpleTiled(SimpleTiledSample {
+                        name: required(sample, "name")?,
+                        subset: optional(sample, "subset")?,
+                        dimensions: dimensions(sample, 24)?,
+                        periodic: optional_bool(sample, "periodic")?.unwrap_or(false),
+                    }));
+                }
+                _ => {}
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl OverlappingSample {
+    /// Pattern size.
+    pub fn size(&self) -> Coordinate2d {
+        Coordinate2d {
+            x: self.n,
+            y: self.n,
+        }
+    }
+
+    /// Codify the patterns of `input`, which is made periodic according to
+    /// [`Self::periodic_input`].
+    ///
+    /// # Errors
+    ///
+    /// If [`Self::symmetry`] isn't from 1 to 8.
+    pub fn codify<T: Clone + PartialEq + Hash + Eq + Tile<Axis2d, ()> + 'static>(
+        &self,
+        input: Grid2d<Option<T>>,
+    ) -> Result<OverlappingModel<T>, XmlError> {
+        if !(1..=8).contains(&self.symmetry) {
+            return Err(XmlError::Invalid(format!(
+                "invalid symmetry {} of sample {:?}",
+                self.symmetry, self.name
+            )));
+        }
+        let input = input
+            .with_periodic(Axis2d::X, self.periodic_input)
+            .with_periodic(Axis2d::Y, self.periodic_input);
+        // The original's ground is the pattern at the last position it looks
+        // at, by its first cell, and patterns here are found by their center
+        // instead.
+        let last = |len: u32| {
+            if self.periodic_input {
+                (len - 1 + self.n / 2) % len
+            } else {
+                len.saturating_sub(self.n) + self.n / 2
+            }
+        };
+        let dimensions = input.dimensions();
+        let ground = self.ground.then(|| Coordinate2d {
+            x: last(dimensions.x),
+            y: last(dimensions.y),
+        });
+        let (rules, ground) = codify_variants::<_, _, Grid2d<StateSet>>(
+            &input,
+            self.size(),
+            ground,
+            |_| true,
+            |pattern| wfc_variants(pattern, self.symmetry),
+        );
+        Ok(OverlappingModel {
+            rules,
+            ground,
+            n: self.n,
+        })
+    }
+}
+
+/// Rules codified from an [`OverlappingSample`].
+pub struct OverlappingModel<T> {
+    rules: SetCollapseRules<ExtractedPatterns<T>>,
+    ground: Option<State>,
+    n: u32,
+}
+
+impl<T> OverlappingModel<T> {
+    /// The rules of the patterns of the sample.
+    pub fn rules(&self) -> &SetCollapseRules<ExtractedPatterns<T>> {
+        &self.rules
+    }
+
+    /// Take the rules of the patterns of the sample.
+    pub fn into_rules(self) -> SetCollapseRules<ExtractedPatterns<T>> {
+        self.rules
+    }
+
+    /// The pattern at the end of the last row of the sample, if it has
+    /// [`OverlappingSample::ground`]. Its first row is the last row of a
+    /// periodic sample, and otherwise its last row is.
+    pub fn ground(&self) -> Option<State> {
+        self.ground
+    }
+
+    /// Constrain the output `space` to have the [`Self::ground`] pattern, if
+    /// any, along its bottom edge, and nowhere else.
+    ///
+    /// Patterns are centered on their cell, so the ground pattern is only
+    /// allowed in the row that puts its first row in the last row of `space`,
+    /// which wraps around to the first `N / 2` rows if `space` is periodic.
+    /// Otherwise, it's centered on the last row.
+    pub fn constrain<S: Superposition>(&self, space: &mut Grid2d<S>) {
+        let Some(ground) = self.ground else {
+            return;
+        };
+        let height = space.dimensions().y;
+        let row = if space.is_periodic(Axis2d::Y) {
+            (height - 1 + self.n / 2) % height
+        } else {
+            height - 1
+        };
+        let dimensions = space.dimensions();
+        for y in 0..dimensions.y {
+            for x in 0..dimensions.x {
+                let cell = &mut space[Coordinate2d { x, y }];
+                if y == row {
+                    cell.retain(|state| state == ground);
+                } else {
+                    cell.remove(ground);
+                }
+            }
+        }
+    }
+}
+
+/// The first `symmetry` of the original's 8 variants of `pattern`, which
+/// alternately reflect the previous variant across the X axis and rotate the
+/// one before it.
+fn wfc_variants<T: Clone + Tile<Axis2d, ()> + 'static>(
+    pattern: Grid2d<Option<T>>,
+    symmetry: u32,
+) -> Vec<Grid2d<Option<T>>> {
+    let size = pattern.dimensions();
+    let reflect = |pattern: &Grid2d<Option<T>>| {
+        Grid2d::new(size, |c| {
+            let c = pattern.flip(c, Axis2d::X)?;
+
+            pattern[c].clone().map(|t| t.flip(Axis2d::X))
+        })
+    };
+    let rotate = |pattern: &Grid2d<Option<T>>| {
+        Grid2d::new(size, |c| {
+            let c = pattern.perp(c, ())?;
+
+            pattern[c].clone().map(|t| t.perp(()))
+        })
+    };
+    let mut variants = vec![pattern];
+    while variants.len() < symmetry as usize {
+        let i = variants.len();
+        let variant = if i % 2 == 1 {
+            reflect(&variants[i - 1])
+        } else {
+            rotate(&variants[i - 2])
+        };
+        variants.push(variant);
+    }
+    variants
+}
+
+/// Convert an orientation index of the original implementation, which rotates
+/// clockwise (when +y is drawn downwards) and then reflects.
+///
+/// Index `i < 4` is `i` of its rotations, which are the opposite way to
+/// [`Orientation`]'s, so `4 - i` of ours. Index `4 + i` reflects that, and
+/// reflecting after rotating one way is the same as reflecting before
+/// rotating the other way, so it's flipped and then `i` of ours.
+fn wfc_orientation(index: u32) -> Orientation {
+    if index < 4 {
+        Orientation::new(4 - index, false)
+    } else {
+        Orientation::new(index - 4, true)
+    }
+}
+
+fn expect_name(node: Node, name: &str) -> Result<(), XmlError> {
+    if node.tag_name().name() == name {
+        Ok(())
+    } else {
+        Err(XmlError::Invalid(format!(
+            "expected <{name}>, found <{}>",
+            node.tag_name().name()
+        )))
+    }
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.has_tag_name(name))
+}
+
+fn optional<T: FromStr>(node: Node, attribute: &str) -> Result<Option<T>, XmlError> {
+    node.attribute(attribute)
+        .map(|value| {
+            value.parse().map_err(|_| {
+                XmlError::Invalid(format!(
+                    "invalid {attribute} {value:?} of <{}>",
+                    node.tag_name().name()
+                ))
+            })
+        })
+        .transpose()
+}
+
+fn required<T: FromStr>(node: Node, attribute: &str) -> Result<T, XmlError> {
+    optional(node, attribute)?.ok_or_else(|| {
+        XmlError::Invalid(format!(
+            "missing {attribute} of <{}>",
+            node.tag_name().name()
+        ))
+    })
+}
+
+/// Booleans are written as `True` and `False`.
+fn optional_bool(node: Node, attribute: &str) -> Result<Option<bool>, XmlError> {
+    optional::<String>(node, attribute)?
+        .map(|value| value.to_ascii_lowercase().parse())
+        .transpose()
+        .map_err(|_| {
+            XmlError::Invalid(format!(
+                "invalid {attribute} of <{}>",
+                node.tag_name().name()
+            ))
+        })
+}
+
+/// `width` and `height`, falling back to `size` and then `default`.
+fn dimensions(node: Node, default: u32) -> Result<Coordinate2d, XmlError> {
+    let size = optional(node, "size")?.unwrap_or(default);
+    Ok(Coordinate2d {
+        x: optional(node, "width")?.unwrap_or(size),
+        y: optional(node, "height")?.unwrap_or(size),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wfc_orientation, Sample, Tileset, XmlError};
+    use crate::{
+        collapse, collapse_backtracking,
+        grid_2d::{Axis2d, Coordinate2d, Direction2d, Grid2d},
+        state::StateSet,
+        tiled::Orientation,
+        verify, Backtracking, Space,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+    use roxmltree::Document;
+    use std::{
+        collections::{BTreeSet, HashMap},
+        num::NonZeroU32,
+    };
+
+    const KNOTS: &str = r#"
+        <set size="10">
+            <tiles>
+                <tile name="corner" symmetry="L" weight="0.5"/>
+                <tile name="cross" symmetry="I"/>
+                <tile name="empty" symmetry="X"/>
+                <tile name="line" symmetry="I"/>
+                <tile name="t" symmetry="T"/>
+            </tiles>
+            <neighbors>
+                <neighbor left="corner 1" right="empty"/>
+                <neighbor left="corner" right="cross"/>
+                <neighbor left="corner" right="line"/>
+                <neighbor left="cross" right="cross"/>
+                <neighbor left="cross" right="line"/>
+                <neighbor left="empty" right="empty"/>
+                <neighbor left="empty" right="line 1"/>
+                <neighbor left="line" right="line"/>
+                <neighbor left="t" right="empty"/>
+                <neighbor left="t 1" right="line"/>
+            </neighbors>
+            <subsets>
+                <subset name="Standard">
+                    <tile name="corner"/>
+                    <tile name="cross"/>
+                    <tile name="empty"/>
+                    <tile name="line"/>
+                </subset>
+                <subset name="Broken">
+                    <tile name="corner"/>
+                    <tile name="knot"/>
+                </subset>
+            </subsets>
+        </set>
+    "#;
+
+    fn invalid(result: Result<Tileset, XmlError>) -> String {
+        match result {
+            Err(XmlError::Invalid(message)) => message,
+            other => panic!("expected invalid model, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_tileset() {
+        let tileset = Tileset::parse(KNOTS, None).unwrap();
+        assert_eq!(tileset.size(), Some(10));
+        assert_eq!(tileset.model().domain().len(), 4 + 2 + 1 + 2 + 4);
+        let corner = tileset.tile("corner").unwrap();
+        assert_eq!(tileset.name(corner), "corner");
+        assert_eq!(tileset.weight(corner), 0.5);
+
+        let model = tileset.model();
+        let line = model.state(tileset.tile("line").unwrap(), wfc_orientation(0));
+        let rules = tileset.rules::<StateSet>();
+        assert!(rules
+            .allowed_in::<Grid2d<StateSet>>(line, Direction2d::Right)
+            .is_some_and(|allowed| allowed.has(line)));
+        let t = model.state(tileset.tile("t").unwrap(), wfc_orientation(0));
+        let empty = model.state(tileset.tile("empty").unwrap(), wfc_orientation(0));
+        assert!(rules
+            .allowed_in::<Grid2d<StateSet>>(t, Direction2d::Right)
+            .is_some_and(|allowed| allowed.has(empty)));
+    }
+
+    #[test]
+    fn parse_subset() {
+        let tileset = Tileset::parse(KNOTS, Some("Standard")).unwrap();
+        assert_eq!(tileset.model().domain().len(), 4 + 2 + 1 + 2);
+        assert_eq!(tileset.tile("t"), None);
+        assert!(tileset.tile("line").is_some());
+    }
+
+    #[test]
+    fn collapse_tileset() {
+        for subset in [None, Some("Standard")] {
+            let tileset = Tileset::parse(KNOTS, subset).unwrap();
+            let rules = tileset.rules::<StateSet>();
+            for seed in 0..4 {
+                let mut space = Grid2d::new(Coordinate2d { x: 8, y: 8 }, |_| rules.domain().all());
+                let mut rng = StdRng::seed_from_u64(seed);
+                collapse_backtracking(
+                    &mut space,
+                    &rules,
+                    &mut rng,
+                    Backtracking::default(),
+                    |_| {},
+                )
+                .unwrap();
+                let verification = verify(&space, &rules);
+                assert!(verification.is_valid(), "{verification}");
+            }
+        }
+    }
+
+    #[test]
+    fn reject_unknown_tiles() {
+        let misspelled = KNOTS.replace(r#"left="t 1""#, r#"left="tee 1""#);
+        assert!(invalid(Tileset::parse(&misspelled, None)).contains("unknown tile \"tee\""));
+        // Unknown even though it's not in the subset.
+        assert!(invalid(Tileset::parse(&misspelled, Some("Standard"))).contains("\"tee\""));
+        assert!(invalid(Tileset::parse(KNOTS, Some("Broken"))).contains("\"knot\""));
+        assert!(invalid(Tileset::parse(KNOTS, Some("Missing"))).contains("no subset"));
+    }
+
+    /// A tile of every symmetry, with neighbors in various orientations.
+    const ASYMMETRIC: &str = r#"
+        <set>
+            <tiles>
+                <tile name="f" symmetry="F"/>
+                <tile name="l" symmetry="L"/>
+                <tile name="t" symmetry="T"/>
+                <tile name="i" symmetry="I"/>
+                <tile name="b" symmetry="\"/>
+                <tile name="x" symmetry="X"/>
+            </tiles>
+            <neighbors>
+                <neighbor left="f 5" right="l 3"/>
+                <neighbor left="f 2" right="f 7"/>
+                <neighbor left="l 1" right="t 2"/>
+                <neighbor left="t 3" right="f 6"/>
+                <neighbor left="i 1" right="b 1"/>
+                <neighbor left="b" right="x"/>
+                <neighbor left="x" right="i"/>
+            </neighbors>
+        </set>
+    "#;
+
+    /// Adjacencies of a tileset as the original implementation computes them,
+    /// as states, direction indices and neighbors.
+    fn original_adjacencies(tileset: &Tileset, xml: &str) -> BTreeSet<(u32, usize, u32)> {
+        let document = Document::parse(xml).unwrap();
+        let set = document.root_element();
+
+        // Its rotation and reflection of images, indexed by row with +y
+        // downwards, which are matched to each `Orientation` by rendering an
+        // asymmetric base image.
+        type Image = [[u32; 3]; 3];
+        type Action = fn(usize) -> usize;
+        let rotate = |src: &Image| {
+            let mut ret = Image::default();
+            for (y, row) in ret.iter_mut().enumerate() {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    *pixel = src[x][2 - y];
+                }
+            }
+            ret
+        };
+        let reflect = |src: &Image| {
+            let mut ret = Image::default();
+            for (y, row) in ret.iter_mut().enumerate() {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    *pixel = src[y][2 - x];
+                }
+            }
+            ret
+        };
+        let mut base = Image::default();
+        for (y, row) in base.iter_mut().enumerate() {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = (x + 3 * y) as u32;
+            }
+        }
+        let render = |orientation: Orientation| {
+            let mut ret = Image::default();
+            for y in 0..3 {
+                for x in 0..3 {
+                    let to = orientation.transform(Coordinate2d { x, y }, 3);
+                    ret[to.y as usize][to.x as usize] = base[y as usize][x as usize];
+                }
+            }
+            ret
+        };
+
+        // The state of each of its tile indices, and their `action` table.
+        let mut states = Vec::new();
+        let mut action = Vec::<[usize; 8]>::new();
+        let mut first = HashMap::new();
+        for tile in set.descendants().filter(|n| n.has_tag_name("tile")) {
+            let name = tile.attribute("name").unwrap();
+            let (cardinality, a, b): (usize, Action, Action) =
+                match tile.attribute("symmetry").unwrap() {
+                    "L" => (
+                        4,
+                        |i| (i + 1) % 4,
+                        |i| if i % 2 == 0 { i + 1 } else { i - 1 },
+                    ),
+                    "T" => (4, |i| (i + 1) % 4, |i| if i % 2 == 0 { i } else { 4 - i }),
+                    "I" => (2, |i| 1 - i, |i| i),
+                    "\\" => (2, |i| 1 - i, |i| 1 - i),
+                    "F" => (
+                        8,
+                        |i| if i < 4 { (i + 1) % 4 } else { 4 + (i - 1) % 4 },
+                        |i| if i < 4 { i + 4 } else { i - 4 },
+                    ),
+                    _ => (1, |i| i, |i| i),
+                };
+            let offset = states.len();
+            first.insert(name, offset);
+            let id = tileset.tile(name).unwrap();
+            let mut images = vec![base];
+            for t in 1..cardinality {
+                images.push(if t < 4 {
+                    rotate(&images[t - 1])
+                } else {
+                    reflect(&images[t - 4])
+                });
+            }
+            for (t, image) in images.iter().enumerate() {
+                let orientation = Orientation::ALL
+                    .into_iter()
+                    .find(|&o| render(o) == *image)
+                    .unwrap();
+                states.push(tileset.model().state(id, orientation).0);
+                let [t1, t2, t3] = [a(t), a(a(t)), a(a(a(t)))];
+                action.push([t, t1, t2, t3, b(t), b(t1), b(t2), b(t3)].map(|i| i + offset));
+            }
+        }
+
+        // Its propagator, where direction 0 is -x and 1 is +y.
+        let index = |d| Grid2d::<StateSet>::direction_index(d);
+        let mut ret = BTreeSet::new();
+        let mut allow = |t: usize, direction: Direction2d, neighbor: usize| {
+            ret.insert((states[t], index(direction), states[neighbor]));
+            ret.insert((states[neighbor], index(-direction), states[t]));
+        };
+        for neighbor in set.descendants().filter(|n| n.has_tag_name("neighbor")) {
+            let [l, r] = ["left", "right"].map(|side| {
+                let variant = neighbor.attribute(side).unwrap();
+                let (name, index) = variant.split_once(' ').unwrap_or((variant, "0"));
+                action[first[name]][index.parse::<usize>().unwrap()]
+            });
+            let (d, u) = (action[l][1], action[r][1]);
+            allow(r, Direction2d::Left, l);
+            allow(action[r][6], Direction2d::Left, action[l][6]);
+            allow(action[l][4], Direction2d::Left, action[r][4]);
+            allow(action[l][2], Direction2d::Left, action[r][2]);
+            allow(u, Direction2d::Up, d);
+            allow(action[d][6], Direction2d::Up, action[u][6]);
+            allow(action[u][4], Direction2d::Up, action[d][4]);
+            allow(action[d][2], Direction2d::Up, action[u][2]);
+        }
+        ret
+    }
+
+    #[test]
+    fn orientations_match_original() {
+        let tileset = Tileset::parse(ASYMMETRIC, None).unwrap();
+        let rules = tileset.rules::<StateSet>();
+        let adjacencies = rules
+            .adjacencies::<Grid2d<_>>()
+            .flat_map(|(state, direction, allowed)| {
+                let index = Grid2d::<StateSet>::direction_index(direction);
+                allowed
+                    .iter()
+                    .map(move |neighbor| (state.0, index, neighbor.0))
+            })
+            .collect::<BTreeSet<_>>();
+        assert_eq!(adjacencies, original_adjacencies(&tileset, ASYMMETRIC));
+    }
+
+    #[test]
+    fn codify_samples() {
+        let samples = Sample::parse_all(
+            r#"
+            <samples>
+                <overlapping name="a" N="2" symmetry="1"/>
+                <overlapping name="b" N="2" symmetry="2"/>
+                <overlapping name="c" N="2" symmetry="3"/>
+                <overlapping name="d" N="2" symmetry="4"/>
+                <overlapping name="e" N="2" symmetry="5"/>
+                <overlapping name="f" N="2" symmetry="6"/>
+                <overlapping name="g" N="2" symmetry="7"/>
+                <overlapping name="h" N="2"/>
+                <overlapping name="i" N="2" symmetry="9"/>
+                <simpletiled name="j" subset="Standard"/>
+            </samples>
+        "#,
+        )
+        .unwrap();
+        assert_eq!(samples.len(), 10);
+        let Sample::SimpleTiled(tiled) = &samples[9] else {
+            panic!("expected simple tiled sample");
+        };
+        assert_eq!(tiled.subset.as_deref(), Some("Standard"));
+
+        // Every pattern and its rotations and reflections are distinct.
+        let input = Grid2d::new(Coordinate2d { x: 3, y: 3 }, |c| {
+            NonZeroU32::new(1 + c.x + 3 * c.y)
+        });
+        let patterns = samples[..9]
+            .iter()
+            .map(|sample| {
+                let Sample::Overlapping(sample) = sample else {
+                    panic!("expected overlapping sample");
+                };
+                sample
+                    .codify(input.clone())
+                    .map(|model| model.rules().domain().len())
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Vec<_>>();
+        for (symmetry, patterns) in (1..=8).zip(&patterns) {
+            assert_eq!(*patterns, Ok(9 * symmetry));
+        }
+        assert!(patterns[8].as_ref().unwrap_err().contains("symmetry 9"));
+    }
+
+    #[test]
+    fn codify_ground() {
+        let samples = Sample::parse_all(
+            r#"
+            <samples>
+                <overlapping name="a" N="3" symmetry="2" ground="True"/>
+            </samples>
+        "#,
+        )
+        .unwrap();
+        let Sample::Overlapping(sample) = &samples[0] else {
+            panic!("expected overlapping sample");
+        };
+        // Sky, a row with a tree in it, and ground.
+        let [sky, tree, ground] = [1, 2, 3].map(NonZeroU32::new);
+        let input = Grid2d::new(Coordinate2d { x: 4, y: 4 }, |c| match c.y {
+            3 => ground,
+            2 if c.x == 1 => tree,
+            _ => sky,
+        });
+        let model = sample.codify(input).unwrap();
+        let rules = model.rules();
+        let ground_state = model.ground().unwrap();
+        assert_eq!(rules.observer().center(ground_state), sky.as_ref());
+
+        let dimensions = Coordinate2d { x: 6, y: 4 };
+        for seed in 0..4 {
+            let mut space = Grid2d::new(dimensions, |_| rules.domain().all())
+                .with_periodic(Axis2d::X, true)
+                .with_periodic(Axis2d::Y, true);
+            model.constrain(&mut space);
+            collapse(&mut space, rules, &mut StdRng::seed_from_u64(seed), |_| {}).unwrap();
+            space.visit_coordinates(|c| {
+                // The pattern whose first row is ground is centered on the
+                // first row, such that the last row is ground.
+                assert_eq!(space[c].has(ground_state), c.y == 0);
+            });
+            let (output, overconstrained) = rules
+                .observer()
+                .decode_superposition::<Grid2d<_>, _>(&space);
+            assert_eq!(overconstrained, 0);
+            output.visit_coordinates(|c| assert_eq!(output[c] == ground, c.y == 3));
+        }
+    }
+}